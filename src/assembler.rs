@@ -1,39 +1,368 @@
 use crate::utils::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use crate::avcmacro::AvcMacro;
+use crate::expr::Expr;
+use crate::object::*;
 
 pub struct Assembler<'a> {
     code: &'a str,
     words: Vec<Word>,
+    /// the expansion each word came from, if any, indexed like `words`
+    origins: Vec<Option<Trace>>,
+    sections: Vec<Section>,
+    /// index of the section words are going into
+    section: usize,
+    /// the scope each section was left in, so it can be picked up again
+    section_scopes: HashMap<String, String>,
+    section_name: String,
     labels: HashMap<String, u16>,
-    macros: HashMap<String, AvcMacro>
+    macros: HashMap<String, AvcMacro>,
+    consts: HashMap<String, i64>,
+    /// field names and offsets
+    structs: HashMap<String, Vec<(String, i64)>>,
+    /// next free address and end of each region `.var` allocates from
+    regions: HashMap<String, (i64, i64)>,
+    /// labels other objects can use, see `.export`
+    exports: Vec<String>,
+    conds: Vec<Cond>,
+    /// whether to drop routines nothing refers to, and how many bytes that saved
+    strip: bool,
+    stripped: usize,
+    /// whether to run the peephole optimiser, and what it changed
+    optimise: bool,
+    rewrites: Vec<String>,
+    /// what gaps in the rom are filled with
+    fill: u8,
+    /// the last plain label, which `&sublabels` are attached to
+    scope: String,
+    /// number of `@@` labels so far
+    anon: usize,
+    /// number of `@@` labels that forward references need
+    anon_needed: usize,
+    expansions: usize,
+    depth: usize,
+    max_depth: usize,
+    /// where the current token came from, ie. its line and the macros it's inside
+    origin: Vec<String>,
+    /// the line of the top level token, and the macros being expanded and the line in each, for tracing
+    /// errors after pass 1
+    line: i32,
+    trace: Vec<(String, i32)>,
+    /// where each word the current top level token expanded to came from
+    expanded: Vec<Trace>,
+    /// expanded tokens and their origins, only kept when preprocessing
+    listing: Option<Vec<(String, String)>>,
 }
 
 impl<'a> Assembler<'a> {
-    pub fn new(code: &'a str) -> Assembler {
+    pub fn new(code: &'a str) -> Assembler<'a> {
         Assembler {
             code,
             words: Vec::new(),
+            origins: Vec::new(),
+            sections: vec![Section { name: "code".into(), addr: Some(0x0300), words: Vec::new(), origins: Vec::new() }], // default program init
+            section: 0,
+            section_scopes: HashMap::new(),
+            section_name: "code".into(),
             labels: HashMap::new(),
-            macros: HashMap::new()
+            macros: HashMap::new(),
+            consts: HashMap::new(),
+            structs: HashMap::new(),
+            regions: HashMap::from([(String::from("zp"), (0, 0x100))]),
+            exports: Vec::new(),
+            conds: Vec::new(),
+            strip: false,
+            stripped: 0,
+            optimise: false,
+            rewrites: Vec::new(),
+            fill: 0,
+            scope: String::new(),
+            anon: 0,
+            anon_needed: 0,
+            expansions: 0,
+            depth: 0,
+            max_depth: 64,
+            origin: Vec::new(),
+            line: 0,
+            trace: Vec::new(),
+            expanded: Vec::new(),
+            listing: None,
         }
     }
+    /// define a constant before assembly, like `.const(name, value)`
+    pub fn define(&mut self, name: &str, value: i64) {
+        self.consts.insert(name.into(), value);
+    }
+    /// set the byte used to fill gaps, like `.fillbyte(byte)`
+    pub fn set_fill(&mut self, fill: u8) {
+        self.fill = fill
+    }
+    /// drop routines and sections that nothing refers to
+    pub fn set_strip(&mut self, strip: bool) {
+        self.strip = strip
+    }
+    /// how many bytes stripping saved
+    pub fn stripped(&self) -> usize {
+        self.stripped
+    }
+    /// rewrite instructions into shorter ones that do the same thing
+    pub fn set_optimise(&mut self, optimise: bool) {
+        self.optimise = optimise
+    }
+    /// each rewrite the optimiser made, and where
+    pub fn rewrites(&self) -> &[String] {
+        &self.rewrites
+    }
+    /// how deeply macros can expand inside each other before giving up
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth
+    }
     pub fn assemble(&mut self) -> Result<Vec<u8>> {
+        println!("tokenising...");
         self.pass_1()?;
         println!("pass 1 completed");
+        if self.strip {
+            self.strip_unused()
+        }
+        if self.optimise {
+            self.peephole()
+        }
+        self.place_sections();
         self.pass_2()?;
         println!("pass 2 completed");
 
         self.pass_3()
     }
+    /// assemble into a relocatable object, leaving anything that depends on where sections go to the linker
+    pub fn assemble_object(&mut self) -> Result<Object> {
+        println!("tokenising...");
+        self.sections[0].addr = None; // the linker decides where code goes
+        self.pass_1()?;
+        println!("pass 1 completed");
+        if self.optimise {
+            self.peephole()
+        }
+        let mut sections = std::mem::take(&mut self.sections);
+
+        let places: Vec<(String, Option<u16>)> = sections.iter().map(|s| (s.name.clone(), s.addr)).collect();
+        let (mut locals, mut aligns) = self.object_labels(&sections)?;
+        // labels exist wherever they end up, so scopes can be resolved before jumps are sorted out
+        for section in &mut sections {
+            resolve_scopes(&mut section.words, &|l| locals.contains_key(l) || self.labels.contains_key(l));
+        }
+        while self.relax_object(&mut sections, &locals, &places) {
+            (locals, aligns) = self.object_labels(&sections)?
+        }
+        let lookup = |n: &str| self.object_lookup(n, &locals, &places);
+
+        let mut obj = Object::default();
+        for (section, align) in sections.iter_mut().zip(aligns) {
+            expand_jumps(&mut section.words, &mut section.origins);
+            let base = section.addr.unwrap_or(0) as usize;
+            let this = Target::Section(section.name.clone());
+            let mut bytes = Vec::new();
+            let mut relocs = Vec::new();
+            let mut counter = base;
+            for word in &section.words {
+                let offset = counter - base;
+                match (word, section.addr) {
+                    (Word::AssertPc(pc), Some(_)) if counter != *pc as usize => return Err(AvcErr::PcMismatch(*pc, counter)),
+                    (Word::AssertPc(pc), None) => return Err(AvcErr::NotRelocatable(format!(".assert_pc({:04x})", pc))),
+                    _ => {}
+                }
+                if word.is_byte_like() && section.addr.is_some() && counter < 0x0300 {
+                    return Err(AvcErr::OpNotInCodeSpace)
+                }
+                let value = match word {
+                    Word::LblCall(l, k) => Some((Expr::Name(l.clone()), *k)),
+                    Word::Expr(e, k) => Some((e.clone(), *k)),
+                    _ => None
+                };
+                match word {
+                    Word::Byte(b) | Word::Op(b) => set_vec_at(&mut bytes, *b, offset, self.fill),
+                    Word::AbsPad(_, Some(f)) | Word::RelPad(_, Some(f)) | Word::Align(_, Some(f)) => {
+                        let end = word.next_offset(counter) - base;
+                        if end > bytes.len() {
+                            bytes.resize(end, *f)
+                        }
+                    }
+                    _ => {}
+                }
+                if let Some((e, kind)) = value {
+                    let (value, target) = e.eval_reloc(&|n| Some(match n {
+                        "$" if section.addr.is_some() => (counter as i64, None),
+                        "$" => (offset as i64, Some(this.clone())),
+                        _ => lookup(n)
+                    }))?;
+                    // relative jumps inside a section, or anywhere in a fixed one, are already known
+                    let known = match &target {
+                        None => kind != LblKind::Rel || section.addr.is_some(),
+                        Some(t) => kind == LblKind::Rel && *t == this
+                    };
+                    if known {
+                        write_value(&mut bytes, value, &kind, counter + 4, offset, self.fill)?
+                    }
+                    else {
+                        let target = target.unwrap_or(Target::Abs);
+                        if let Target::Symbol(s) = &target {
+                            if !obj.imports.contains(s) {
+                                obj.imports.push(s.clone())
+                            }
+                        }
+                        relocs.push(Reloc { offset, kind, target, addend: value });
+                        let width = if kind == LblKind::Abs { 2 } else { 1 };
+                        for i in 0..width { // the linker fills these in
+                            set_vec_at(&mut bytes, 0, offset + i, self.fill)
+                        }
+                    }
+                }
+                counter = word.next_offset(counter)
+            }
+            obj.sections.push(ObjSection {
+                name: std::mem::take(&mut section.name),
+                addr: section.addr,
+                align,
+                size: counter - base,
+                bytes,
+                relocs
+            })
+        }
+
+        for name in &self.exports {
+            let (value, target) = match lookup(name) {
+                (_, Some(Target::Symbol(_))) => return Err(AvcErr::UndefinedLabel(name.clone())),
+                place => place
+            };
+            let section = match target {
+                Some(Target::Section(s)) => Some(s),
+                _ => None
+            };
+            obj.exports.push((name.clone(), section, value))
+        }
+
+        Ok(obj)
+    }
+    /// where each label in an object is. they're offsets into their section, unless it has a fixed address.
+    /// also returns the alignment each section needs
+    fn object_labels(&self, sections: &[Section]) -> Result<(Locals, Vec<u16>)> {
+        let mut locals = HashMap::new();
+        let mut aligns = Vec::new();
+        for (i, section) in sections.iter().enumerate() {
+            let base = section.addr.unwrap_or(0) as usize;
+            let mut counter = base;
+            let mut max_align = 1;
+            for word in &section.words {
+                if let Word::Lbl(l) = word {
+                    if locals.insert(l.clone(), (i, counter - base)).is_some() || self.labels.contains_key(l) {
+                        return Err(AvcErr::DuplicateLabel(l.clone()))
+                    }
+                }
+                match (word, section.addr) {
+                    (Word::AbsPad(p, _), addr) if addr.is_none() || (*p as usize) < base => {
+                        return Err(AvcErr::NotRelocatable(format!(".abspad({:04x})", p)))
+                    }
+                    (Word::Align(amt, _), _) => max_align = max_align.max(*amt),
+                    _ => {}
+                }
+                counter = word.next_offset(counter)
+            }
+            aligns.push(max_align)
+        }
+        Ok((locals, aligns))
+    }
+    /// the value of a name in an object, as far as we know. `places` is the name and address of each section
+    fn object_lookup(&self, n: &str, locals: &Locals, places: &[(String, Option<u16>)]) -> (i64, Option<Target>) {
+        if let Some(&(j, offset)) = locals.get(n) {
+            match places[j] {
+                (_, Some(addr)) => (addr as i64 + offset as i64, None),
+                (ref name, None) => (offset as i64, Some(Target::Section(name.clone())))
+            }
+        }
+        else if let Some(addr) = self.labels.get(n) {
+            (*addr as i64, None)
+        }
+        else if let Some(v) = self.consts.get(n) {
+            (*v, None)
+        }
+        else {
+            (0, Some(Target::Symbol(n.into())))
+        }
+    }
+    /// like `relax`, but a jump can only be short if its target is in the same section
+    fn relax_object(&self, sections: &mut [Section], locals: &Locals, places: &[(String, Option<u16>)]) -> bool {
+        let mut changed = false;
+        for section in sections {
+            let base = section.addr.unwrap_or(0) as usize;
+            let this = Target::Section(section.name.clone());
+            let mut counter = base;
+            for word in &mut section.words {
+                if let Word::Jump(e, _, long @ false) = word {
+                    let value = e.eval_reloc(&|n| Some(match n {
+                        "$" if section.addr.is_some() => (counter as i64 + 1, None),
+                        "$" => ((counter - base) as i64 + 1, Some(this.clone())),
+                        _ => self.object_lookup(n, locals, places)
+                    }));
+                    let reaches = match value {
+                        Ok((v, None)) if section.addr.is_some() => jump_reaches(v, counter),
+                        Ok((v, Some(t))) if t == this => jump_reaches(v, counter - base),
+                        _ => false
+                    };
+                    if !reaches {
+                        *long = true;
+                        changed = true
+                    }
+                }
+                counter = word.next_offset(counter)
+            }
+        }
+        changed
+    }
+    /// labels and constants, one per line, for debuggers and the like
+    pub fn symbols(&self) -> String {
+        let mut syms: Vec<(i64, &str)> = self.labels.iter().map(|(n, a)| (*a as i64, n.as_str()))
+            .chain(self.consts.iter().map(|(n, v)| (*v, n.as_str())))
+            .collect();
+        syms.sort();
+        syms.iter().map(|(v, n)| if *v < 0 {
+            format!("-{:04x} {}\n", -v, n)
+        }
+        else {
+            format!("{:04x} {}\n", v, n)
+        }).collect()
+    }
+    /// expand macros without assembling, and write the result back out as source
+    pub fn preprocess(&mut self) -> Result<String> {
+        self.listing = Some(Vec::new());
+        self.pass_1()?;
+
+        let mut ret = String::new();
+        let mut line: Vec<&str> = Vec::new();
+        let mut last_origin = None;
+        for (token, origin) in self.listing.as_ref().unwrap() {
+            if let Some(last) = last_origin {
+                if last != origin {
+                    ret.push_str(&format!("{} // {}\n", line.join(" "), last));
+                    line.clear()
+                }
+            }
+            line.push(token);
+            last_origin = Some(origin)
+        }
+        if let Some(origin) = last_origin {
+            ret.push_str(&format!("{} // {}\n", line.join(" "), origin))
+        }
+
+        Ok(ret)
+    }
     /// tokenise and parse
     fn pass_1(&mut self) -> Result<()> {
         let t = Tokeniser::new(self.code);
-        self.words.push(Word::AbsPad(0x0300)); // implicit pad to default program init
-        println!("tokenising...");
         for token in t {
             let token = token?;
             //println!("{}", token);
+            self.origin = vec![format!("line {}", token.line)];
+            self.line = token.line;
             let words = match self.token_to_words(token.token) {
                 Ok(v) => v,
                 Err(e) => {
@@ -41,36 +370,268 @@ impl<'a> Assembler<'a> {
                     return Err(e)
                 }
             };
-            for w in words {
-                self.words.push(w)
+            // words from macros know where they came from, everything else came from this line
+            let traces = std::mem::take(&mut self.expanded);
+            if traces.len() == words.len() {
+                for (w, t) in words.into_iter().zip(traces) {
+                    self.push_word(w, Some(t))
+                }
             }
+            else {
+                for w in words {
+                    self.push_word(w, None)
+                }
+            }
+        }
+        if !self.conds.is_empty() {
+            return Err(AvcErr::UnterminatedIf)
+        }
+        if self.anon_needed > self.anon {
+            return Err(AvcErr::UndefinedLabel("+".into()))
         }
 
         Ok(())
     }
+    /// drop routines, and sections other than `code` without a fixed address, that can't be reached from the
+    /// entry point or anything else that's kept
+    fn strip_unused(&mut self) {
+        let mut labels: HashSet<String> = self.labels.keys().cloned().collect();
+        for word in self.sections.iter().flat_map(|s| &s.words) {
+            if let Word::Lbl(l) = word {
+                labels.insert(l.clone());
+            }
+        }
+        for section in &mut self.sections {
+            resolve_scopes(&mut section.words, &|l| labels.contains(l))
+        }
+
+        // split sections into blocks at the start and end of each routine
+        let mut blocks = Vec::new();
+        for (i, section) in self.sections.iter_mut().enumerate() {
+            let entry = section.words.iter().position(|w| w.is_byte_like());
+            let mut block = Block { section: i, start: 0, end: 0, routine: false, root: false, labels: Vec::new(), refs: Vec::new() };
+            for (j, word) in section.words.iter_mut().enumerate() {
+                match word {
+                    Word::RoutineStart | Word::RoutineEnd => {
+                        let routine = matches!(word, Word::RoutineStart);
+                        let next = Block { section: i, start: j, end: j, routine, root: false, labels: Vec::new(), refs: Vec::new() };
+                        blocks.push(std::mem::replace(&mut block, next))
+                    }
+                    Word::Lbl(l) => block.labels.push(l.clone()),
+                    Word::LblCall(l, _) => block.refs.push(l.clone()),
+                    Word::Expr(e, _) | Word::Jump(e, ..) => e.names_mut(&mut |n| block.refs.push(n.clone())),
+                    _ => {}
+                }
+                block.end = j + 1;
+                // execution starts at the first byte of code
+                block.root |= i == 0 && entry == Some(j)
+            }
+            blocks.push(block)
+        }
+        for b in &mut blocks {
+            let section = &self.sections[b.section];
+            b.root |= !b.routine && (b.section == 0 || section.addr.is_some())
+        }
+
+        let defined_in: HashMap<&str, usize> = blocks.iter().enumerate()
+            .flat_map(|(i, b)| b.labels.iter().map(move |l| (l.as_str(), i)))
+            .collect();
+        let mut kept: Vec<bool> = blocks.iter().map(|b| b.root).collect();
+        let mut todo: Vec<usize> = (0..blocks.len()).filter(|i| kept[*i]).collect();
+        while let Some(i) = todo.pop() {
+            for r in &blocks[i].refs {
+                if let Some(&j) = defined_in.get(r.as_str()) {
+                    if !kept[j] {
+                        kept[j] = true;
+                        todo.push(j)
+                    }
+                }
+            }
+        }
+
+        for (b, _) in blocks.iter().zip(kept).filter(|(_, k)| !k).rev() {
+            let section = &mut self.sections[b.section];
+            section.origins.drain(b.start..b.end);
+            let words: Vec<Word> = section.words.drain(b.start..b.end).collect();
+            let size: usize = words.iter().map(|w| match w {
+                Word::AbsPad(..) | Word::Align(..) => 0,
+                w => w.next_offset(0)
+            }).sum();
+            if size > 0 {
+                let name = b.labels.first().unwrap_or(&self.sections[b.section].name);
+                println!("stripping {} ({} bytes)", name, size);
+                self.stripped += size
+            }
+        }
+    }
+    /// rewrite runs of instructions with nothing else between them, like labels
+    fn peephole(&mut self) {
+        for section in &mut self.sections {
+            let words = &mut section.words;
+            let origins = &mut section.origins;
+            let mut i = 0;
+            while i < words.len() {
+                let (len, new, what) = match &words[i..] {
+                    [Word::Op(0x80), Word::Byte(a), Word::Op(0x80), Word::Byte(b), ..] => {
+                        (4, vec![Word::Op(0xa0), Word::Byte(*a), Word::Byte(*b)], "LIT LIT -> LIT2")
+                    }
+                    [Word::Op(0xc0), Word::Byte(a), Word::Op(0xc0), Word::Byte(b), ..] => {
+                        (4, vec![Word::Op(0xe0), Word::Byte(*a), Word::Byte(*b)], "LITr LITr -> LITr2")
+                    }
+                    [Word::Op(0x2c), Word::Op(0x6a), ..] => (2, vec![Word::Op(0x2a)], "JSR2 JMP2r -> JMP2"),
+                    [Word::Op(0x0c), Word::Op(0x6a), ..] => (2, vec![Word::Op(0x0a)], "JSR JMP2r -> JMP"),
+                    [Word::Jump(e, 0x0c, long), Word::Op(0x6a), ..] => (2, vec![Word::Jump(e.clone(), 0x0a, *long)], ".jsr JMP2r -> .jmp"),
+                    // any modes but k, as long as they match
+                    [Word::Op(d), Word::Op(p), ..] if *d & 0x9f == 0x06 && *p == *d - 3 => (2, Vec::new(), "DUP POP removed"),
+                    _ => {
+                        i += 1;
+                        continue
+                    }
+                };
+                // say where it happened, as an offset from the last label
+                let (name, start) = words[..i].iter().enumerate().rev()
+                    .find_map(|(j, w)| match w {
+                        Word::Lbl(l) => Some((l.as_str(), j)),
+                        _ => None
+                    })
+                    .unwrap_or((&section.name, 0));
+                let offset = words[start..i].iter().fold(0, |c, w| w.next_offset(c));
+                let rewrite = format!("{}+{:x}: {}", name, offset, what);
+                println!("optimising {}", rewrite);
+                self.rewrites.push(rewrite);
+                origins.splice(i..i + len, vec![origins[i].clone(); new.len()]);
+                words.splice(i..i + len, new);
+                // the rewrite might have made a new match with what came before
+                i = i.saturating_sub(3)
+            }
+        }
+    }
+    /// put every section's words in one list, in the order they go in the rom
+    fn place_sections(&mut self) {
+        // sections without a fixed address go one after another after code, then fixed ones go where they're told
+        let (mut fixed, chained): (Vec<_>, Vec<_>) = std::mem::take(&mut self.sections).into_iter()
+            .enumerate()
+            .partition(|(i, s)| *i != 0 && s.addr.is_some());
+        fixed.sort_by_key(|(_, s)| s.addr);
+        for (_, section) in chained.into_iter().chain(fixed) {
+            self.words.push(Word::Section(section.name, section.addr));
+            self.origins.push(None);
+            if let Some(addr) = section.addr {
+                self.words.push(Word::AbsPad(addr, None));
+                self.origins.push(None)
+            }
+            self.words.extend(section.words);
+            self.origins.extend(section.origins)
+        }
+    }
+    fn push_word(&mut self, w: Word, origin: Option<Trace>) {
+        match w {
+            Word::Section(name, addr) => {
+                self.section = match self.sections.iter().position(|s| s.name == name) {
+                    Some(i) => i,
+                    None => {
+                        self.sections.push(Section { name, addr: None, words: Vec::new(), origins: Vec::new() });
+                        self.sections.len() - 1
+                    }
+                };
+                if addr.is_some() {
+                    self.sections[self.section].addr = addr
+                }
+            }
+            w => {
+                let section = &mut self.sections[self.section];
+                section.words.push(w);
+                section.origins.push(origin)
+            }
+        }
+    }
     /// run through and calculate labels
     fn pass_2(&mut self) -> Result<()> {
+        // `.var` labels are already known
+        let vars = self.labels.clone();
+        self.find_labels()?;
+        resolve_scopes(&mut self.words, &|l| self.labels.contains_key(l));
+        // moving labels can put more jumps out of range, so go until nothing changes
+        while self.relax() {
+            self.labels = vars.clone();
+            self.find_labels()?
+        }
+        expand_jumps(&mut self.words, &mut self.origins);
+
+        let mut counter = 0;
+        // the name, start and end of each section
+        let mut ranges: Vec<(&str, usize, usize)> = Vec::new();
+        for (word, origin) in self.words.iter().zip(&self.origins) {
+            if let Word::Lbl(l) = word {
+                println!("label {} at {:04x}", l, counter)
+            }
+            if let Word::AssertPc(pc) = word {
+                if counter != *pc as usize {
+                    return Err(traced(AvcErr::PcMismatch(*pc, counter), origin))
+                }
+            }
+            if let Word::Section(name, addr) = word {
+                let start = addr.map(|a| a as usize).unwrap_or(counter);
+                ranges.push((name, start, start));
+                continue
+            }
+            counter = word.next_offset(counter);
+            if let Some((_, start, end)) = ranges.last_mut() {
+                if counter > *start {
+                    *end = (*end).max(counter)
+                }
+            }
+        }
+        for (i, (name, start, end)) in ranges.iter().enumerate() {
+            if ranges[..i].iter().any(|(_, s, e)| start < e && s < end) {
+                return Err(AvcErr::SectionOverlap(name.to_string()))
+            }
+        }
+
+        Ok(())
+    }
+    fn find_labels(&mut self) -> Result<()> {
         let mut counter = 0;
-        for word in &self.words {
+        for (word, origin) in self.words.iter().zip(&self.origins) {
             if let Word::Lbl(l) = word {
-                println!("label {} at {:04x}", l, counter);
-                self.labels.insert(l.clone(), counter as u16);
+                if self.labels.insert(l.clone(), counter as u16).is_some() {
+                    return Err(traced(AvcErr::DuplicateLabel(l.clone()), origin))
+                }
             }
             if word.is_byte_like() && counter < 0x0300 { // in zpg/stack
                 println!("{:?}", word);
-                return Err(AvcErr::OpNotInCodeSpace)
+                return Err(traced(AvcErr::OpNotInCodeSpace, origin))
             }
             counter = word.next_offset(counter);
         }
-
         Ok(())
     }
+    /// make jumps that can't reach their target with a relative address long, returning whether any changed
+    fn relax(&mut self) -> bool {
+        let mut changed = false;
+        let mut counter = 0;
+        for word in &mut self.words {
+            if let Word::Jump(e, _, long @ false) = word {
+                let value = e.eval(&|n| match n {
+                    "$" => Some(counter as i64 + 1),
+                    _ => self.labels.get(n).map(|a| *a as i64).or_else(|| self.consts.get(n).copied())
+                });
+                // undefined labels are left for pass 3 to complain about
+                if value.is_ok_and(|v| !jump_reaches(v, counter)) {
+                    *long = true;
+                    changed = true
+                }
+            }
+            counter = word.next_offset(counter)
+        }
+        changed
+    }
     /// bytes!
     fn pass_3(&mut self) -> Result<Vec<u8>> {
         // rom header
         let mut ret = vec![0x41, 0x56, 0x43, 0x00];
         let mut counter = 4; // start at 4 to compensate for header
-        for word in &self.words {
+        for (word, origin) in self.words.iter().zip(&self.origins) {
             //println!("{:?}", word);
             let counter_inner = if counter >= 0x0300 { // if we're in code space ie. adding bytes, shift counter down
                 counter - 0x0300
@@ -79,26 +640,23 @@ impl<'a> Assembler<'a> {
                 counter
             };
             match word {
-                Word::Byte(b) => set_vec_at(&mut ret, *b, counter_inner),
+                Word::Byte(b) | Word::Op(b) => set_vec_at(&mut ret, *b, counter_inner, self.fill),
                 Word::LblCall(l, k) => {
-                    let addr = self.labels.get(l).ok_or_else(|| AvcErr::UndefinedLabel(l.clone()))?;
-                    match k {
-                        LblKind::Abs => {
-                            let [hb, lb] = (addr).to_be_bytes();
-                            set_vec_at(&mut ret, hb, counter_inner);
-                            set_vec_at(&mut ret, lb, counter_inner + 1);
-                        }
-                        LblKind::Rel => {
-                            // signed 8 bit
-                            // if counter > addr, jump forwards ie. ctr - addr
-                            // if addr > counter, jump back ie. (addr - ctr) * -1
-                            // sub 1 to account for Things
-
-                            let rel: i8 = (((*addr as isize) - (counter as isize)) - 1)
-                                .try_into().map_err(|_| AvcErr::RelJumpTooLarge)?;
-                            set_vec_at(&mut ret, rel as u8, counter_inner)
-                        }
-                        _ => unimplemented!()
+                    let addr = self.labels.get(l).ok_or_else(|| traced(AvcErr::UndefinedLabel(l.clone()), origin))?;
+                    write_value(&mut ret, *addr as i64, k, counter, counter_inner, self.fill).map_err(|e| traced(e, origin))?
+                }
+                Word::Expr(e, k) => {
+                    let value = e.eval(&|n| match n {
+                        "$" => Some(counter as i64 - 4), // header
+                        _ => self.labels.get(n).map(|a| *a as i64).or_else(|| self.consts.get(n).copied())
+                    }).map_err(|e| traced(e, origin))?;
+                    write_value(&mut ret, value, k, counter, counter_inner, self.fill).map_err(|e| traced(e, origin))?
+                }
+                Word::AbsPad(_, Some(f)) | Word::RelPad(_, Some(f)) | Word::Align(_, Some(f)) => {
+                    // fill the gap explicitly, but only where nothing has been written yet
+                    let end = word.next_offset(counter - 4) + 4;
+                    if end > 0x0300 && end - 0x0300 > ret.len() {
+                        ret.resize(end - 0x0300, *f)
                     }
                 }
                 _ => {}
@@ -111,6 +669,14 @@ impl<'a> Assembler<'a> {
 
     fn token_to_words(&mut self, s: &str) -> Result<Vec<Word>> { // returns a vec because macros and strings
         //println!("parsing {}", s);
+        if self.process_conditional(s)? {
+            return Ok(Vec::new())
+        }
+        if let Some(listing) = &mut self.listing {
+            if !is_preprocessor(s) {
+                listing.push((s.into(), self.origin.join(", ")))
+            }
+        }
         match &s[..1] { // tokens should never be 0 length
             "." => {
                 self.process_directive(s[1..].into())
@@ -131,11 +697,49 @@ impl<'a> Assembler<'a> {
                     Err(AvcErr::MultibyteChar(c))
                 }
             }
+            "@" if s == "@@" => { // anonymous label
+                self.anon += 1;
+                Ok(vec![Word::Lbl(format!("@@{}", self.anon - 1))])
+            }
             "@" => {
-                Ok(vec![Word::LblCall(s[1..].into(), LblKind::Abs)])
+                Ok(vec![Word::LblCall(self.qualify(&s[1..])?, LblKind::Abs)])
             }
             "^" => {
-                Ok(vec![Word::LblCall(s[1..].into(), LblKind::Rel)])
+                Ok(vec![Word::LblCall(self.qualify(&s[1..])?, LblKind::Rel)])
+            }
+            "=" => { // literal value, LIT or LIT2 depending on size, eg. `=1234` or `=2r:#12`
+                let (modes, value) = s[1..].split_once(':').unwrap_or(("", &s[1..]));
+                let value = self.eval_const(value)?;
+                let width = if modes.contains('2') {
+                    2
+                }
+                else if modes.contains('1') || (-0x80..=0xff).contains(&value) {
+                    1
+                }
+                else {
+                    2
+                };
+                let modes = modes.replace(['1', '2'], "");
+                if width == 1 {
+                    Ok(vec![Word::Op(op_to_byte(&format!("LIT{}", modes))?), Word::Byte(to_byte(value)?)])
+                }
+                else {
+                    let [hb, lb] = to_short(value)?.to_be_bytes();
+                    Ok(vec![Word::Op(op_to_byte(&format!("LIT2{}", modes))?), Word::Byte(hb), Word::Byte(lb)])
+                }
+            }
+            ";" | "," => { // literal address, with optional modes eg. `;r:label`
+                let (modes, name) = s[1..].split_once(':').unwrap_or(("", &s[1..]));
+                let (op, kind) = if s.starts_with(';') {
+                    (format!("LIT2{}", modes), LblKind::Abs)
+                }
+                else {
+                    (format!("LIT{}", modes), LblKind::Rel)
+                };
+                Ok(vec![Word::Op(op_to_byte(&op)?), Word::LblCall(self.qualify(name)?, kind)])
+            }
+            "-" => { // zero page address
+                Ok(vec![Word::LblCall(self.qualify(&s[1..])?, LblKind::Zpg)])
             }
             "%" => {
                 match s[1..].split_once('(') {
@@ -149,27 +753,139 @@ impl<'a> Assembler<'a> {
             }
             _ => {
                 let op = op_to_byte(s)?;
-                Ok(vec![Word::Byte(op)])
+                Ok(vec![Word::Op(op)])
+            }
+        }
+    }
+    /// handle `.if` and friends, returning true if the token should be skipped
+    fn process_conditional(&mut self, s: &str) -> Result<bool> {
+        let active = self.conds.last().map(|c| c.active).unwrap_or(true);
+        let (directive_name, args) = match s.strip_prefix('.') {
+            Some(dir) => split_directive(dir)?,
+            None => return Ok(!active)
+        };
+        let args = args.trim();
+        match directive_name {
+            "if" | "ifdef" | "ifndef" => {
+                let cond = active && match directive_name {
+                    "if" => self.eval_const(args)? != 0,
+                    "ifdef" => self.consts.contains_key(args),
+                    _ => !self.consts.contains_key(args)
+                };
+                self.conds.push(Cond { active: cond, taken: cond, parent_active: active, had_else: false })
+            }
+            "elif" | "else" => {
+                let unmatched = || AvcErr::UnmatchedConditional(format!(".{}", directive_name));
+                let top = self.conds.len().checked_sub(1).ok_or_else(unmatched)?;
+                let c = &self.conds[top];
+                if c.had_else {
+                    return Err(unmatched())
+                }
+                let cond = c.parent_active && !c.taken && (directive_name == "else" || self.eval_const(args)? != 0);
+                let c = &mut self.conds[top];
+                c.active = cond;
+                c.taken |= cond;
+                c.had_else = directive_name == "else"
             }
+            "endif" => {
+                self.conds.pop().ok_or_else(|| AvcErr::UnmatchedConditional(".endif".into()))?;
+            }
+            _ => return Ok(!active)
+        }
+        Ok(true)
+    }
+    /// constants can only be defined once, like labels
+    fn define_const(&mut self, name: &str, value: i64) -> Result<()> {
+        if self.consts.insert(name.into(), value).is_some() {
+            return Err(AvcErr::DuplicateConst(name.into()))
         }
+        Ok(())
+    }
+    fn eval_const(&self, expr: &str) -> Result<i64> {
+        Expr::parse(expr)?.eval(&|n| self.consts.get(n).copied())
     }
+
+    /// expand `&sublabel` to `scope/sublabel`, and `+` or `-` to the anonymous label they refer to
+    fn qualify(&mut self, name: &str) -> Result<String> {
+        if !name.is_empty() && name.chars().all(|c| c == '+') {
+            let idx = self.anon + name.len() - 1;
+            self.anon_needed = self.anon_needed.max(idx + 1);
+            return Ok(format!("@@{}", idx))
+        }
+        if !name.is_empty() && name.chars().all(|c| c == '-') {
+            let idx = self.anon.checked_sub(name.len()).ok_or_else(|| AvcErr::UndefinedLabel(name.into()))?;
+            return Ok(format!("@@{}", idx))
+        }
+        match name.strip_prefix('&') {
+            Some(sub) if self.scope.is_empty() => Err(AvcErr::NoScope(sub.into())),
+            Some(sub) => Ok(format!("{}/{}", self.scope, sub)),
+            None => Ok(name.into())
+        }
+    }
+
+    fn label(&mut self, name: &str) -> Result<Vec<Word>> {
+        let name = self.qualify(name)?;
+        let mut ret = Vec::new();
+        if !(name.contains('/') || name.contains('?')) { // macro locals and sublabels don't start scopes
+            self.scope = name.clone();
+            ret.push(Word::Scope(name.clone()))
+        }
+        ret.push(Word::Lbl(name));
+        Ok(ret)
+    }
+    /// a label, or an expression to be worked out once labels are known
+    fn reference(&mut self, s: &str, kind: LblKind) -> Result<Word> {
+        if is_plain_label(s) {
+            return Ok(Word::LblCall(self.qualify(s)?, kind))
+        }
+        let mut e = Expr::parse(s)?;
+        let mut err = None;
+        e.names_mut(&mut |n| match self.qualify(n) {
+            Ok(q) => *n = q,
+            Err(e) => err = Some(e)
+        });
+        match err {
+            Some(e) => Err(e),
+            None => Ok(Word::Expr(e, kind))
+        }
+    }
+
     // shave off . before calling
     fn process_directive(&mut self, dir: &str) -> Result<Vec<Word>> {
-        let (directive_name, args) = dir.split_once('(').ok_or_else(|| AvcErr::MalformedDirective(String::from(dir)))?;
-        if !args.ends_with(')') { // not actually sure this can happen but it doesn't hurt to check
-            return Err(AvcErr::MalformedDirective(String::from(dir)))
-        }
-        let args = &args[..args.len() - 1];
+        let (directive_name, args) = split_directive(dir)?;
         let mut ret = Vec::new();
         match directive_name {
             "label" | "lbl" => {
-                ret.push(Word::Lbl(args.into()))
+                ret.append(&mut self.label(args)?)
+            }
+            "jmp" | "jnz" | "jsr" => { // the shortest jump that reaches, eg. `.jmp(loop)`
+                let op = op_to_byte(&directive_name.to_uppercase())?;
+                let target = match self.reference(args, LblKind::Rel)? {
+                    Word::LblCall(l, _) => Expr::Name(l),
+                    Word::Expr(e, _) => e,
+                    _ => unreachable!()
+                };
+                ret.push(Word::Jump(target, op, false))
+            }
+            "routine" => { // a label that can be stripped, along with everything up to the next routine
+                ret.push(Word::RoutineStart);
+                ret.append(&mut self.label(args)?)
+            }
+            "endroutine" => {
+                ret.push(Word::RoutineEnd)
             }
             "absc" | "abscall" => {
-                ret.push(Word::LblCall(args.into(), LblKind::Abs))
+                ret.push(self.reference(args, LblKind::Abs)?)
             }
             "relcall" => {
-                ret.push(Word::LblCall(args.into(), LblKind::Rel))
+                ret.push(self.reference(args, LblKind::Rel)?)
+            }
+            "zpc" | "zpcall" => {
+                ret.push(self.reference(args, LblKind::Zpg)?)
+            }
+            "assert_pc" => {
+                let pc = u16::from_str_radix(args, 16).map_err(|_| AvcErr::BadInt(String::from(args)))?;
+                ret.push(Word::AssertPc(pc))
             }
             "hex" | "x" => {
                 let b = u8::from_str_radix(args, 16).map_err(|_| AvcErr::BadInt(String::from(args)))?;
@@ -191,25 +907,228 @@ impl<'a> Assembler<'a> {
                 ret.push(Word::Byte(b))
             }
             "s" => {
-                ret.extend(args.bytes().map(Word::Byte)) // LEVERAGE
+                if args.trim_start().starts_with('"') {
+                    ret.extend(parse_string(args.trim())?.into_iter().map(Word::Byte))
+                }
+                else {
+                    ret.extend(args.bytes().map(Word::Byte)) // LEVERAGE
+                }
+            }
+            "sz" => { // nul terminated
+                ret.extend(parse_string(args.trim())?.into_iter().map(Word::Byte));
+                ret.push(Word::Byte(0))
+            }
+            "sp" => { // length prefixed
+                let s = parse_string(args.trim())?;
+                ret.push(Word::Byte(to_byte(s.len() as i64)?));
+                ret.extend(s.into_iter().map(Word::Byte))
+            }
+            "db" | "dw" => {
+                let kind = if directive_name == "db" { LblKind::Zpg } else { LblKind::Abs };
+                for item in split_bracket_groups(args, ',') {
+                    if item.starts_with('"') {
+                        for b in parse_string(item)? {
+                            if kind == LblKind::Abs { // one char per word
+                                ret.push(Word::Byte(0))
+                            }
+                            ret.push(Word::Byte(b))
+                        }
+                        continue
+                    }
+                    match self.eval_const(item) {
+                        Ok(v) if kind == LblKind::Zpg => ret.push(Word::Byte(to_byte(v)?)),
+                        Ok(v) => ret.extend(to_short(v)?.to_be_bytes().map(Word::Byte)),
+                        Err(AvcErr::UndefinedSymbol(_)) => ret.push(self.reference(item, kind)?), // labels
+                        Err(e) => return Err(e)
+                    }
+                }
+            }
+            "fill" => {
+                let (count, value) = args.split_once(',').ok_or_else(|| AvcErr::MalformedDirective(String::from(dir)))?;
+                let value = to_byte(self.eval_const(value)?)?;
+                let count = self.eval_const(count)?;
+                let count = u16::try_from(count).map_err(|_| AvcErr::ValueTooLarge(count))?;
+                for _ in 0..count {
+                    ret.push(Word::Byte(value))
+                }
+            }
+            "res" => { // reserve space without filling it
+                let count = self.eval_const(args)?;
+                let count = u16::try_from(count).map_err(|_| AvcErr::ValueTooLarge(count))?;
+                ret.push(Word::RelPad(count, None))
+            }
+            "abspad" | "relpad" | "align" => { // with an optional fill byte, eg. `.abspad(0400, ff)`
+                let (amt, fill) = match args.split_once(',') {
+                    Some((amt, fill)) => {
+                        let fill = fill.trim();
+                        (amt.trim(), Some(u8::from_str_radix(fill, 16).map_err(|_| AvcErr::BadInt(String::from(fill)))?))
+                    }
+                    None => (args, None)
+                };
+                let amt = u16::from_str_radix(amt, 16).map_err(|_| AvcErr::BadInt(String::from(amt)))?;
+                ret.push(match directive_name {
+                    "abspad" => Word::AbsPad(amt, fill),
+                    "relpad" => Word::RelPad(amt, fill),
+                    _ => Word::Align(amt, fill)
+                })
+            }
+            "fillbyte" => {
+                self.fill = u8::from_str_radix(args, 16).map_err(|_| AvcErr::BadInt(String::from(args)))?
+            }
+            "section" => { // eg. `.section(data)` or `.section(zeropage, 0000)`
+                let (name, addr) = match args.split_once(',') {
+                    Some((name, addr)) => {
+                        let addr = addr.trim();
+                        (name.trim(), Some(u16::from_str_radix(addr, 16).map_err(|_| AvcErr::BadInt(String::from(addr)))?))
+                    }
+                    None => (args.trim(), None)
+                };
+                // each section keeps its own scope
+                let old = std::mem::replace(&mut self.section_name, name.into());
+                let scope = self.section_scopes.remove(name).unwrap_or_default();
+                self.section_scopes.insert(old, std::mem::replace(&mut self.scope, scope.clone()));
+                ret.push(Word::RoutineEnd);
+                ret.push(Word::Section(name.into(), addr));
+                ret.push(Word::Scope(scope))
+            }
+            "export" => { // only matters when assembling an object
+                for name in split_bracket_groups(args, ',') {
+                    let name = self.qualify(name.trim())?;
+                    self.exports.push(name)
+                }
             }
-            "abspad" => {
-                let pad = u16::from_str_radix(args, 16).map_err(|_| AvcErr::BadInt(String::from(args)))?;
-                ret.push(Word::AbsPad(pad))
+            "region" => { // eg. `.region(ram, #0200, #0300)`, end is exclusive
+                let segments = split_bracket_groups(args, ',');
+                if segments.len() != 3 {
+                    return Err(AvcErr::MalformedDirective(String::from(dir)))
+                }
+                let (start, end) = (self.eval_const(segments[1])?, self.eval_const(segments[2])?);
+                self.regions.insert(segments[0].into(), (start, end));
+            }
+            "var" => { // eg. `.var(ptr, 2)` or `.var(buf, 64, ram)`
+                let segments = split_bracket_groups(args, ',');
+                let (name, size, region) = match segments[..] {
+                    [name, size] => (name, size, "zp"),
+                    [name, size, region] => (name, size, region),
+                    _ => return Err(AvcErr::MalformedDirective(String::from(dir)))
+                };
+                let size = self.eval_const(size)?;
+                let (next, end) = self.regions.get_mut(region).ok_or_else(|| AvcErr::UndefinedRegion(region.into()))?;
+                if size < 0 || *next + size > *end {
+                    return Err(AvcErr::RegionFull(region.into(), name.into()))
+                }
+                let addr = to_short(*next)?;
+                *next += size;
+                if self.labels.insert(name.into(), addr).is_some() {
+                    return Err(AvcErr::DuplicateLabel(name.into()))
+                }
+            }
+            "enum" => { // eg. `.enum((A, B, C=8, D), 1, 2)`
+                let segments = split_bracket_groups(args, ',');
+                let (names, start, step) = match segments[..] {
+                    [names] => (names, "0", "1"),
+                    [names, start] => (names, start, "1"),
+                    [names, start, step] => (names, start, step),
+                    _ => return Err(AvcErr::MalformedDirective(String::from(dir)))
+                };
+                let mut value = self.eval_const(start)?;
+                let step = self.eval_const(step)?;
+                for name in split_bracket_groups(unwrap_group(names), ',') {
+                    let name = match name.split_once('=') {
+                        Some((name, v)) => {
+                            value = self.eval_const(v)?;
+                            name.trim()
+                        }
+                        None => name
+                    };
+                    self.define_const(name, value)?;
+                    value += step
+                }
+            }
+            "struct" => { // eg. `.struct(header, (link: 2, flags: 1))`
+                let (name, fields) = args.split_once(',').ok_or_else(|| AvcErr::MalformedDirective(String::from(dir)))?;
+                let name = name.trim();
+                let mut offset = 0;
+                let mut layout = Vec::new();
+                for field in split_bracket_groups(unwrap_group(fields.trim()), ',') {
+                    let (field, size) = field.split_once(':').ok_or_else(|| AvcErr::MalformedDirective(String::from(dir)))?;
+                    let field = field.trim();
+                    self.define_const(&format!("{}/{}", name, field), offset)?;
+                    layout.push((field.into(), offset));
+                    let size = self.eval_const(size)?;
+                    if size < 0 {
+                        return Err(AvcErr::ValueTooLarge(size))
+                    }
+                    offset += size
+                }
+                self.define_const(name, offset)?;
+                self.structs.insert(name.into(), layout);
             }
-            "relpad" => {
-                let pad = u16::from_str_radix(args, 16).map_err(|_| AvcErr::BadInt(String::from(args)))?;
-                ret.push(Word::RelPad(pad))
+            "inst" => { // reserve space for a struct, with labels for each field
+                let (label, name) = args.split_once(',').ok_or_else(|| AvcErr::MalformedDirective(String::from(dir)))?;
+                let name = name.trim();
+                let layout = self.structs.get(name).ok_or_else(|| AvcErr::UndefinedStruct(name.into()))?.clone();
+                let size = self.consts[name];
+                ret.append(&mut self.label(label.trim())?);
+                let label = self.qualify(label.trim())?;
+                let mut last = 0;
+                for (field, offset) in layout { // fields are in order, so just pad between them
+                    ret.push(Word::RelPad(to_short(offset - last)?, None));
+                    ret.push(Word::Lbl(format!("{}/{}", label, field)));
+                    last = offset
+                }
+                ret.push(Word::RelPad(to_short(size - last)?, None))
             }
-            "align" => {
-                let amt = u16::from_str_radix(args, 16).map_err(|_| AvcErr::BadInt(String::from(args)))?;
-                ret.push(Word::Align(amt))
+            "const" => {
+                let (name, value) = args.split_once(',').ok_or_else(|| AvcErr::MalformedDirective(String::from(dir)))?;
+                let value = self.eval_const(value)?;
+                self.define_const(name.trim(), value)?;
             }
             "defmac" => {
                 let m = AvcMacro::new(args)?;
                 let m_name = args.split_once(',').unwrap().0;
                 self.macros.insert(m_name.into(), m);
             }
+            "each" => {
+                let segments = split_bracket_groups(args, ',');
+                if segments.len() != 3 {
+                    return Err(AvcErr::MalformedDirective(String::from(dir)))
+                }
+                let trig = format!("${}", segments[0]);
+                let items = unwrap_group(segments[1]);
+                let body = unwrap_group(segments[2]);
+                if !items.is_empty() {
+                    for item in split_bracket_groups(items, ',') {
+                        ret.append(&mut self.process_expanded_macro(&body.replace(&trig, unwrap_group(item)), ".each")?)
+                    }
+                }
+            }
+            "rept" => {
+                let (count, body) = args.split_once(',').ok_or_else(|| AvcErr::MalformedDirective(String::from(dir)))?;
+                let body = unwrap_group(body.trim());
+                for _ in 0..self.eval_const(count)? {
+                    ret.append(&mut self.process_expanded_macro(body, ".rept")?)
+                }
+            }
+            "for" => {
+                let segments = split_bracket_groups(args, ',');
+                let (var, start, end, step, body) = match segments[..] {
+                    [var, start, end, body] => (var, start, end, "1", body),
+                    [var, start, end, step, body] => (var, start, end, step, body),
+                    _ => return Err(AvcErr::MalformedDirective(String::from(dir)))
+                };
+                let trig = format!("${}", var);
+                let (start, end, step) = (self.eval_const(start)?, self.eval_const(end)?, self.eval_const(step)?);
+                if step == 0 {
+                    return Err(AvcErr::MalformedDirective(String::from(dir)))
+                }
+                let body = unwrap_group(body);
+                let mut i = start;
+                while (step > 0 && i < end) || (step < 0 && i > end) {
+                    ret.append(&mut self.process_expanded_macro(&body.replace(&trig, &i.to_string()), ".for")?);
+                    i += step
+                }
+            }
             _ => return Err(AvcErr::UnrecognisedDirective(String::from(dir)))
         }
         Ok(ret)
@@ -217,42 +1136,231 @@ impl<'a> Assembler<'a> {
 
     fn expand_macro(&mut self, name: &str, args: &str) -> Result<Vec<Word>> {
         let m = self.macros.get(name).ok_or_else(|| AvcErr::UndefinedMacro(name.into()))?;
-        let args_s = args.split(',').map(|a| a.trim()).collect();
-        let m_exp = m.expand(args_s);
+        let args_s = if args.trim().is_empty() {
+            Vec::new()
+        }
+        else {
+            // commas in a bracketed fragment separate tokens, so `(LIT #01, LIT #02)` is two instructions
+            split_bracket_groups(args, ',').into_iter()
+                .map(|a| if a.starts_with('(') { split_bracket_groups(unwrap_group(a), ',').join(" ") } else { unwrap_group(a).into() })
+                .collect::<Vec<String>>()
+        };
+        let m_exp = m.expand(args_s.iter().map(String::as_str).collect(), self.expansions)?;
+        self.expansions += 1;
 
-        self.process_expanded_macro(&m_exp)
+        self.process_expanded_macro(&m_exp, &format!("%{}", name))
     }
 
-    fn process_expanded_macro(&mut self, mac: &str) -> Result<Vec<Word>> {
-        let mut ret= Vec::new();
+    /// `name` is used to trace errors back through nested expansions
+    fn process_expanded_macro(&mut self, mac: &str, name: &str) -> Result<Vec<Word>> {
+        if self.depth >= self.max_depth {
+            return Err(AvcErr::MacroDepth(self.max_depth))
+        }
+        let conds = self.conds.len();
+        self.depth += 1;
+        self.origin.push(name.into());
+        let mut ret = Vec::new();
+        // the line that first left conditionals unbalanced, if any
+        let mut unbalanced = None;
         let t = Tokeniser::new(mac);
         for token in t {
             let token = token?;
-            ret.append(&mut self.token_to_words(token.token)?)
+            self.trace.push((name.into(), token.line));
+            let nested = self.expanded.len();
+            let res = self.token_to_words(token.token);
+            match res {
+                Ok(mut v) => {
+                    // words from nested macros are already traced
+                    if self.expanded.len() - nested != v.len() {
+                        let trace = Rc::new((self.line, self.trace.clone()));
+                        self.expanded.truncate(nested);
+                        self.expanded.extend(std::iter::repeat_n(trace, v.len()))
+                    }
+                    self.trace.pop();
+                    ret.append(&mut v);
+                    if self.conds.len() != conds {
+                        unbalanced.get_or_insert(token.line);
+                    }
+                    else {
+                        unbalanced = None
+                    }
+                }
+                Err(e) => {
+                    self.depth -= 1;
+                    self.origin.pop();
+                    self.trace.pop();
+                    return Err(AvcErr::InExpansion(name.into(), token.line, Box::new(e)))
+                }
+            }
+        }
+        self.depth -= 1;
+        self.origin.pop();
+        if let Some(line) = unbalanced { // conditionals can't cross macro boundaries
+            return Err(AvcErr::InExpansion(name.into(), line, Box::new(AvcErr::UnterminatedIf)))
         }
         Ok(ret)
     }
 }
 
+/// whether a directive argument is a bare label rather than an expression
+fn is_plain_label(s: &str) -> bool {
+    !s.starts_with(|c: char| c.is_ascii_digit()) && s.chars().all(|c| !(c.is_whitespace() || "$()*+~!<>=|^%'#".contains(c))) ||
+    s.chars().all(|c| c == '+') // anonymous
+}
+
+/// split `name(args)` into name and args. the brackets can be left off if there are no args
+fn split_directive(dir: &str) -> Result<(&str, &str)> {
+    match dir.split_once('(') {
+        Some((name, args)) => {
+            let args = args.strip_suffix(')').ok_or_else(|| AvcErr::MalformedDirective(String::from(dir)))?;
+            Ok((name, args))
+        }
+        None => Ok((dir, ""))
+    }
+}
+
+/// whether a short jump at `counter` can reach `target`. the relative address comes after the `LIT`
+fn jump_reaches(target: i64, counter: usize) -> bool {
+    i8::try_from(target - (counter as i64 + 1 + 4) - 1).is_ok()
+}
+
+/// wrap an error after pass 1 in the macro calls the word it's about came from
+fn traced(e: AvcErr, origin: &Option<Trace>) -> AvcErr {
+    match origin {
+        Some(t) => {
+            let (line, calls) = &**t;
+            let e = calls.iter().rev().fold(e, |e, (name, line)| AvcErr::InExpansion(name.clone(), *line, Box::new(e)));
+            AvcErr::OnLine(*line, Box::new(e))
+        }
+        None => e
+    }
+}
+
+/// turn jumps into the instructions they stand for, keeping `origins` in step
+fn expand_jumps(words: &mut Vec<Word>, origins: &mut Vec<Option<Trace>>) {
+    if !words.iter().any(|w| matches!(w, Word::Jump(..))) {
+        return
+    }
+    for (w, origin) in std::mem::take(words).into_iter().zip(std::mem::take(origins)) {
+        match w {
+            Word::Jump(e, op, long) => {
+                let (lit, kind, op) = if long { (0xa0, LblKind::Abs, op | 0x20) } else { (0x80, LblKind::Rel, op) };
+                words.push(Word::Op(lit));
+                words.push(match e {
+                    Expr::Name(l) => Word::LblCall(l, kind),
+                    e => Word::Expr(e, kind)
+                });
+                words.push(Word::Op(op));
+                origins.extend([origin.clone(), origin.clone(), origin])
+            }
+            w => {
+                words.push(w);
+                origins.push(origin)
+            }
+        }
+    }
+}
+
+/// resolve labels inside the current scope first
+fn resolve_scopes(words: &mut [Word], exists: &dyn Fn(&str) -> bool) {
+    let mut scope = "";
+    for word in words {
+        let mut resolve = |l: &mut String| {
+            if !scope.is_empty() && !l.contains('/') {
+                let scoped = format!("{}/{}", scope, l);
+                if exists(&scoped) {
+                    *l = scoped
+                }
+            }
+        };
+        match word {
+            Word::Scope(s) => scope = s,
+            Word::LblCall(l, _) => resolve(l),
+            Word::Expr(e, _) | Word::Jump(e, ..) => e.names_mut(&mut resolve),
+            _ => {}
+        }
+    }
+}
+
+/// the section and offset of each label in an object
+type Locals = HashMap<String, (usize, usize)>;
+/// the line of the top level token a word was expanded from, then the macros it came through, outermost first,
+/// and the line in each
+type Trace = Rc<(i32, Vec<(String, i32)>)>;
+
+/// part of a section, for working out what's unused
+struct Block {
+    section: usize,
+    /// range of words
+    start: usize,
+    end: usize,
+    routine: bool,
+    /// kept whether anything refers to it or not
+    root: bool,
+    labels: Vec<String>,
+    refs: Vec<String>,
+}
+
+struct Section {
+    name: String,
+    addr: Option<u16>,
+    words: Vec<Word>,
+    origins: Vec<Option<Trace>>,
+}
+
+struct Cond {
+    /// whether tokens are currently being assembled
+    active: bool,
+    /// whether any branch has been assembled yet
+    taken: bool,
+    parent_active: bool,
+    /// whether `.else` has been seen, after which only `.endif` can come
+    had_else: bool,
+}
+
+/// tokens that get expanded away before assembly
+fn is_preprocessor(s: &str) -> bool {
+    ["%", ".defmac(", ".each(", ".rept(", ".for("].iter().any(|p| s.starts_with(p))
+}
+
 #[derive(Debug)]
 enum Word {
-    Byte(u8), // ops, literals, everything
+    Byte(u8), // literals, data, everything
+    /// an instruction, which the optimiser can rewrite
+    Op(u8),
 
     Lbl(String),
     LblCall(String, LblKind),
+    /// start of a label scope, takes no space
+    Scope(String),
+    /// an expression involving labels or `$`, written out like a label of the same kind
+    Expr(Expr, LblKind),
+    /// check the current address
+    AssertPc(u16),
+    /// switch section, with an optional fixed address. after pass 1, marks where each section starts
+    Section(String, Option<u16>),
+    /// a jump, call or conditional jump with the opcode given, using a relative address if it's in range and
+    /// an absolute one if it isn't. only exists until pass 2
+    Jump(Expr, u8, bool),
+    /// bounds of a block that can be stripped, see `.routine`
+    RoutineStart,
+    RoutineEnd,
 
-    AbsPad(u16),
-    RelPad(u16),
-    Align(u16),
+    /// pads have an optional byte to fill the gap with
+    AbsPad(u16, Option<u8>),
+    RelPad(u16, Option<u8>),
+    Align(u16, Option<u8>),
 }
 impl Word {
     fn next_offset(&self, cur: usize) -> usize {
         match self {
-            Word::RelPad(p) => cur + *p as usize,
-            Word::AbsPad(p) => *p as usize,
-            Word::Lbl(_) => cur,
-            Word::LblCall(_, LblKind::Abs) => cur + 2,
-            Word::Align(amt) => {
+            Word::RelPad(p, _) => cur + *p as usize,
+            Word::AbsPad(p, _) => *p as usize,
+            Word::Lbl(_) | Word::Scope(_) | Word::AssertPc(_) | Word::Section(..) | Word::RoutineStart | Word::RoutineEnd => cur,
+            Word::LblCall(_, LblKind::Abs) | Word::Expr(_, LblKind::Abs) => cur + 2,
+            Word::Jump(_, _, false) => cur + 3,
+            Word::Jump(_, _, true) => cur + 4,
+            Word::Align(amt, _) => {
                 align(cur, *amt)
             }
             _ => cur + 1
@@ -261,28 +1369,16 @@ impl Word {
     fn is_byte_like(&self) -> bool {
         !(
             matches!(self, Word::Lbl(_)) ||
-            matches!(self, Word::AbsPad(_)) ||
-            matches!(self, Word::RelPad(_))
+            matches!(self, Word::Scope(_)) ||
+            matches!(self, Word::AssertPc(_)) ||
+            matches!(self, Word::Section(..)) ||
+            matches!(self, Word::RoutineStart) ||
+            matches!(self, Word::RoutineEnd) ||
+            matches!(self, Word::AbsPad(..)) ||
+            matches!(self, Word::RelPad(..))
         )
     }
 }
-#[allow(dead_code)]
-#[derive(Debug)]
-enum LblKind {
-    Abs, Rel, Zpg
-}
-// round cur up to the next multiple of amt
-fn align(cur: usize, amt: u16) -> usize {
-    let amtu = amt as usize;
-    if cur % amtu == 0 {
-        cur
-    }
-    else {
-        let muls = cur / amtu;
-        (muls + 1) * amtu
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -294,4 +1390,284 @@ mod tests {
         assert_eq!(align(48, 16), 48);
         assert_eq!(align(28, 5), 30);
     }
+    #[test]
+    fn mac_local_labels() {
+        let mut a = Assembler::new(".defmac(L, (), (.lbl(?l) LIT ^?l JMP)) %L %L");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x80, 0xfa, 0x0a, 0x80, 0xfa, 0x0a]));
+        let mut a = Assembler::new(".defmac(Q, (), (.s(why?))) %Q");
+        assert_eq!(a.assemble(), Ok(b"AVC\0why?".to_vec()));
+        let mut a = Assembler::new(".lbl(x) .lbl(x)");
+        assert_eq!(a.assemble(), Err(AvcErr::DuplicateLabel("x".into())))
+    }
+    #[test]
+    fn mac_grouped_args() {
+        let mut a = Assembler::new(".defmac(TWICE, (x, y), ($x $x $y)) .defmac(ONE, (v), (LIT #$v))
+            %TWICE((LIT #01, LIT #02), {')}) %TWICE(%ONE(05), {'(})");
+        assert_eq!(a.assemble(), Ok(vec![
+            0x41, 0x56, 0x43, 0x00,
+            0x80, 0x01, 0x80, 0x02, 0x80, 0x01, 0x80, 0x02, b')',
+            0x80, 0x05, 0x80, 0x05, b'(',
+        ]))
+    }
+    #[test]
+    fn quoted_brackets() {
+        let mut a = Assembler::new(".defmac(E, (c), (LIT $c)) LIT '{ JMP \"{abc NOP %E('}) NOP");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x80, b'{', 0x0a, b'{', b'a', b'b', b'c', 0x00, 0x80, b'}', 0x00]))
+    }
+    #[test]
+    fn mac_each() {
+        let mut a = Assembler::new(".defmac(PUSH, (first, rest...), (LIT #$first .each(b, ($rest), (LIT #$b)) LIT #0$#)) %PUSH(01, 02, 03)");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x80, 0x01, 0x80, 0x02, 0x80, 0x03, 0x80, 0x02]))
+    }
+    #[test]
+    fn preprocess() {
+        let mut a = Assembler::new(".defmac(EMIT, (c), (LIT '$c LIT2 #ff #09 STA))\nNOP\n%EMIT(a) .each(x, (1, 2), (#0$x))");
+        assert_eq!(a.preprocess(), Ok(String::from(
+            "NOP // line 1\nLIT 'a LIT2 #ff #09 STA // line 2, %EMIT\n#01 #02 // line 2, .each\n"
+        )))
+    }
+    #[test]
+    fn conditionals() {
+        let src = ".const(TARGET, 2)
+            .ifdef(DEBUG) #01 .if(TARGET == 1) #02 .else #03 .endif .endif
+            .if(TARGET == 1) #04 .elif(TARGET == 2) #05 .elif(1) #06 .else #07 .endif
+            .defmac(M, (x), (.if($x) #08 .else #09 .endif)) %M(0) %M(1)";
+        let mut a = Assembler::new(src);
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x05, 0x09, 0x08]));
+        let mut a = Assembler::new(src);
+        a.define("DEBUG", 1);
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x01, 0x03, 0x05, 0x09, 0x08]));
+        let mut a = Assembler::new(".if(1) #01");
+        assert_eq!(a.assemble(), Err(AvcErr::UnterminatedIf));
+        let mut a = Assembler::new(".endif");
+        assert_eq!(a.assemble(), Err(AvcErr::UnmatchedConditional(".endif".into())));
+        // defines win over defaults, but can't be overwritten
+        let mut a = Assembler::new(".ifndef(T) .const(T, 1) .endif .db(T)");
+        a.define("T", 2);
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x02]));
+        let mut a = Assembler::new(".const(T, 1)");
+        a.define("T", 2);
+        assert_eq!(a.assemble(), Err(AvcErr::DuplicateConst("T".into())));
+        let mut a = Assembler::new(".if(1) #01 .else #02 .else #03 .endif");
+        assert_eq!(a.assemble(), Err(AvcErr::UnmatchedConditional(".else".into())));
+        let mut a = Assembler::new(".if(0) #01 .else #02 .elif(1) #03 .endif");
+        assert_eq!(a.assemble(), Err(AvcErr::UnmatchedConditional(".elif".into())));
+    }
+    #[test]
+    fn repetition() {
+        let mut a = Assembler::new(".const(N, 2) .rept(N + 1, (DUP)) .for(i, 0, 3, (.if($i != 1) LIT '$i .endif)) .for(i, 4, 0, -2, (#0$i))");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x06, 0x06, 0x06, 0x80, b'0', 0x80, b'2', 0x04, 0x02]))
+    }
+    #[test]
+    fn scopes() {
+        let mut a = Assembler::new(".lbl(foo) .lbl(&loop) LIT ^&loop JMP .lbl(bar) .lbl(&loop) LIT ^loop JMP .absc(foo/loop) .lbl(loop) .absc(loop)");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x80, 0xfa, 0x0a, 0x80, 0xfa, 0x0a, 0x03, 0x00, 0x03, 0x08]));
+        let mut a = Assembler::new(".lbl(&x)");
+        assert_eq!(a.assemble(), Err(AvcErr::NoScope("x".into())));
+    }
+    #[test]
+    fn anon_labels() {
+        let mut a = Assembler::new("@@ LIT ^- JMP LIT ^+ JMP @@ LIT ^++ JMP @@ @@ .absc(--)");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x80, 0xfa, 0x0a, 0x80, 0xfd, 0x0a, 0x80, 0xfd, 0x0a, 0x03, 0x09]));
+        let mut a = Assembler::new("LIT ^- JMP");
+        assert_eq!(a.assemble(), Err(AvcErr::UndefinedLabel("-".into())));
+        let mut a = Assembler::new("@@ LIT ^++ JMP @@");
+        assert_eq!(a.assemble(), Err(AvcErr::UndefinedLabel("+".into())));
+    }
+    #[test]
+    fn current_address() {
+        let mut a = Assembler::new(".lbl(t) .absc($) .absc(end - t) .assert_pc(0304) LIT .relcall($ + 2) .lbl(end)");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x03, 0x00, 0x00, 0x06, 0x80, 0xfd]));
+        let mut a = Assembler::new("NOP .assert_pc(0300)");
+        assert_eq!(a.assemble(), Err(AvcErr::PcMismatch(0x300, 0x301)));
+        // numbers aren't labels
+        let mut a = Assembler::new(".absc(0x0304) .jmp(0x300)");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x03, 0x04, 0x80, 0xf8, 0x0a]));
+    }
+    #[test]
+    fn literal_runes() {
+        let mut a = Assembler::new(".lbl(x) ;x JMP2 ,x JMP ;r:x ,r:x");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0xa0, 0x03, 0x00, 0x2a, 0x80, 0xf6, 0x0a, 0xe0, 0x03, 0x00, 0xc0, 0xf0]));
+        let mut a = Assembler::new(".lbl(x) ;k:x");
+        assert_eq!(a.assemble(), Err(AvcErr::BadMode("LIT2k".into(), 'k')));
+    }
+    #[test]
+    fn literal_values() {
+        let mut a = Assembler::new(".const(PORT, #ff09) =104 =PORT =#12 =2:#12 =r:-1 =2r:300");
+        assert_eq!(a.assemble(), Ok(vec![
+            0x41, 0x56, 0x43, 0x00,
+            0x80, 0x68, 0xa0, 0xff, 0x09, 0x80, 0x12, 0xa0, 0x00, 0x12, 0xc0, 0xff, 0xe0, 0x01, 0x2c
+        ]));
+        let mut a = Assembler::new("=1:300");
+        assert_eq!(a.assemble(), Err(AvcErr::ValueTooLarge(300)));
+    }
+    #[test]
+    fn strings() {
+        let mut a = Assembler::new(r#".s(a b) .s("(c)\n") .sz("d e") .sp("\x00g")"#);
+        assert_eq!(a.assemble(), Ok(b"AVC\0a b(c)\nd e\0\x02\0g".to_vec()));
+        let mut a = Assembler::new(r#".defmac(S, (), (.sz("a? b") .s("ok?"))) .defmac(E, (c), (LIT $c)) %S %E('") NOP NOP"#);
+        assert_eq!(a.assemble(), Ok(b"AVC\0a? b\0ok?\x80\"\0\0".to_vec()));
+        // `"word` is still shorthand inside brackets, unless it's an argument
+        let mut a = Assembler::new(r#".defmac(M, (w), ("hi $w NOP)) %M(("yo)) .rept(1, ("ok)) .db("a", 1)"#);
+        assert_eq!(a.assemble(), Ok(b"AVC\0hiyo\0ok\x61\x01".to_vec()));
+    }
+    #[test]
+    fn data_lists() {
+        let mut a = Assembler::new(".const(N, 3) .db(1, #ff, 0b10, 'a, N * 2, end - start, \"hi\") .dw(#1234, start, N, \"a\") .lbl(start) .fill(N, -1) .res(2) .lbl(end) .db(0)");
+        assert_eq!(a.assemble(), Ok(vec![
+            0x41, 0x56, 0x43, 0x00,
+            0x01, 0xff, 0x02, b'a', 0x06, 0x05, b'h', b'i',
+            0x12, 0x34, 0x03, 0x10, 0x00, 0x03, 0x00, b'a',
+            0xff, 0xff, 0xff, 0x00, 0x00, 0x00
+        ]));
+        let mut a = Assembler::new(".res(-2)");
+        assert_eq!(a.assemble(), Err(AvcErr::ValueTooLarge(-2)));
+        let mut a = Assembler::new(".fill(-3, 1)");
+        assert_eq!(a.assemble(), Err(AvcErr::ValueTooLarge(-3)));
+        let mut a = Assembler::new(".fill(#10000, 1)");
+        assert_eq!(a.assemble(), Err(AvcErr::ValueTooLarge(0x10000)));
+    }
+    #[test]
+    fn fill_bytes() {
+        let mut a = Assembler::new("NOP .relpad(2) NOP .align(8, 03) .abspad(030a, ee) .abspad(0301) .relpad(3, 11) .fillbyte(ff) .abspad(030c) NOP");
+        assert_eq!(a.assemble(), Ok(vec![
+            0x41, 0x56, 0x43, 0x00,
+            0x00, 0xff, 0xff, 0x00, 0x03, 0x03, 0x03, 0x03, 0xee, 0xee, 0xff, 0xff, 0x00
+        ]));
+    }
+    #[test]
+    fn structs() {
+        let mut a = Assembler::new(".struct(hdr, (link: 2, flags: 1, name: 4, code: 2)) .db(hdr, hdr/name) .inst(w, hdr) .dw(w/code, &flags)");
+        assert_eq!(a.assemble(), Ok(vec![
+            0x41, 0x56, 0x43, 0x00,
+            0x09, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x03, 0x09, 0x03, 0x04
+        ]));
+    }
+    #[test]
+    fn variables() {
+        let mut a = Assembler::new(".var(ptr, 2) .var(flag, 1) .region(ram, #0200, #0204) .var(buf, 4, ram) LIT -flag LDZ .zpc(ptr + 1) .dw(buf)");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x80, 0x02, 0x10, 0x01, 0x02, 0x00]));
+        let mut a = Assembler::new(".region(zp, 0, 3) .var(ptr, 2) .var(flag, 2)");
+        assert_eq!(a.assemble(), Err(AvcErr::RegionFull("zp".into(), "flag".into())));
+        let mut a = Assembler::new(".lbl(x) LIT -x");
+        assert_eq!(a.assemble(), Err(AvcErr::ValueTooLarge(0x300)));
+    }
+    #[test]
+    fn enums() {
+        let mut a = Assembler::new(".enum((A, B)) .enum((C, D, E=#10, F), 1, -2) .db(A, B, C, D, E, F) .lbl(x)");
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0, 1, 1, 0xff, 0x10, 0x0e]));
+        assert_eq!(a.symbols(), "-0001 D\n0000 A\n0001 B\n0001 C\n000e F\n0010 E\n0306 x\n");
+        let mut a = Assembler::new(".enum((A, B)) .enum((A))");
+        assert_eq!(a.assemble(), Err(AvcErr::DuplicateConst("A".into())));
+        let mut a = Assembler::new(".struct(hdr, (a: 1)) .enum((B, hdr))");
+        assert_eq!(a.assemble(), Err(AvcErr::DuplicateConst("hdr".into())));
+        let mut a = Assembler::new(".const(N, 1) .const(N, 1)");
+        assert_eq!(a.assemble(), Err(AvcErr::DuplicateConst("N".into())));
+    }
+    #[test]
+    fn sections() {
+        let src = ".section(zeropage, 0000) .lbl(zvar) .res(1)
+            .section(code) .lbl(main) LIT -zvar LDZ ;msg
+            .section(data) .lbl(msg) .sz(\"hi\")
+            .section(code) .lbl(&end) .relcall(msg) .absc(main/end)
+            .section(vectors, 0380) .absc(main)";
+        let mut a = Assembler::new(src);
+        let mut rom = vec![0x41, 0x56, 0x43, 0x00, 0x80, 0x00, 0x10, 0xa0, 0x03, 0x09, 0xfe, 0x03, 0x06, b'h', b'i', 0];
+        rom.resize(0x84, 0);
+        rom.extend([0x03, 0x00]);
+        assert_eq!(a.assemble(), Ok(rom));
+        let mut a = Assembler::new("NOP NOP NOP .section(v, 0301) #aa");
+        assert_eq!(a.assemble(), Err(AvcErr::SectionOverlap("v".into())));
+        let mut a = Assembler::new(".section(a, 0400) #01 #02 .section(b, 0401) #03");
+        assert_eq!(a.assemble(), Err(AvcErr::SectionOverlap("b".into())));
+    }
+    #[test]
+    fn strip() {
+        let src = ".lbl(main) ;used JSR2 .absc(table)
+            .routine(unused) ;helper JSR2 JMP2r
+            .routine(used) ;helper JSR2 JMP2r
+            .routine(helper) NOP JMP2r .endroutine
+            .lbl(after) NOP
+            .routine(a) ;b JSR2 .routine(b) ;a JSR2
+            .section(data) .lbl(table) .db(1, 2)
+            .section(strings) .lbl(msg) .sz(\"unused\")";
+        let mut a = Assembler::new(src);
+        a.set_strip(true);
+        let mut b = Assembler::new(".lbl(main) ;used JSR2 .absc(table)
+            .lbl(used) ;helper JSR2 JMP2r
+            .lbl(helper) NOP JMP2r
+            .lbl(after) NOP
+            .section(data) .lbl(table) .db(1, 2)");
+        assert_eq!(a.assemble(), b.assemble());
+        assert_eq!(a.stripped(), 5 + 4 + 4 + 7);
+        // the routine at the entry point is always kept
+        let mut a = Assembler::new(".routine(start) NOP");
+        a.set_strip(true);
+        assert_eq!(a.assemble(), Ok(vec![0x41, 0x56, 0x43, 0x00, 0x00]));
+    }
+    #[test]
+    fn jumps() {
+        let mut a = Assembler::new(".lbl(loop) NOP .jmp(loop) .jnz(&end) .jsr(loop + 1) .lbl(&end)");
+        let mut b = Assembler::new(".lbl(loop) NOP LIT ^loop JMP LIT ^&end JNZ LIT .relcall(loop + 1) JSR .lbl(&end)");
+        assert_eq!(a.assemble(), b.assemble());
+        let mut a = Assembler::new(".lbl(start) .jmp(far) .fill(200, 0) .lbl(far) .jsr(start)");
+        let mut b = Assembler::new(".lbl(start) ;far JMP2 .fill(200, 0) .lbl(far) ;start JSR2");
+        assert_eq!(a.assemble(), b.assemble());
+        // the second jump getting longer puts the first one out of range
+        let mut a = Assembler::new(".jmp(x) .jmp(far) .fill(127, 0) .lbl(x) .fill(200, 0) .lbl(far) .assert_pc(044f)");
+        let mut b = Assembler::new(";x JMP2 ;far JMP2 .fill(127, 0) .lbl(x) .fill(200, 0) .lbl(far)");
+        assert_eq!(a.assemble(), b.assemble());
+        // in an object, jumps out of the section are always long
+        let mut a = Assembler::new(".lbl(l) .jmp(l) .jmp(print) .jnz(msg) .section(data) .lbl(msg)");
+        let obj = a.assemble_object().unwrap();
+        assert_eq!(obj.sections[0].bytes, vec![0x80, 0xfa, 0x0a, 0xa0, 0, 0, 0x2a, 0xa0, 0, 0, 0x2b]);
+        assert_eq!(obj.sections[0].relocs.len(), 2);
+    }
+    #[test]
+    fn peephole() {
+        let src = ".lbl(main) LIT #01 LIT #02 LITr #03 LITr #04 DUP POP DUP2r POP2r DUPk POP ;f JSR2 JMP2r
+            .lbl(f) LIT ^f JSR JMP2r .jsr(f) JMP2r
+            =1 .lbl(x) =2 .db(#80, 1, #80, 2) DUP DUP POP POP LIT #05 LIT #06 LIT #07 LIT #08";
+        let mut a = Assembler::new(src);
+        a.set_optimise(true);
+        let mut b = Assembler::new(".lbl(main) LIT2 #01 #02 LITr2 #03 #04 DUPk POP ;f JMP2
+            .lbl(f) LIT ^f JMP .jmp(f)
+            =1 .lbl(x) =2 .db(#80, 1, #80, 2) LIT2 #05 #06 LIT2 #07 #08");
+        assert_eq!(a.assemble(), b.assemble());
+        assert_eq!(a.rewrites(), [
+            "main+0: LIT LIT -> LIT2", "main+3: LITr LITr -> LITr2", "main+6: DUP POP removed", "main+6: DUP POP removed",
+            "main+b: JSR2 JMP2r -> JMP2", "f+2: JSR JMP2r -> JMP", "f+3: .jsr JMP2r -> .jmp",
+            "x+7: DUP POP removed", "x+6: DUP POP removed", "x+6: LIT LIT -> LIT2", "x+9: LIT LIT -> LIT2",
+        ]);
+    }
+    #[test]
+    fn mac_trace() {
+        let mut a = Assembler::new(".defmac(A, (), (NOP\n %B)) .defmac(B, (), (BAD)) %A");
+        assert_eq!(a.assemble(), Err(AvcErr::InExpansion("%A".into(), 1, Box::new(
+            AvcErr::InExpansion("%B".into(), 0, Box::new(AvcErr::BadInstr("BAD".into())))
+        ))));
+        let mut a = Assembler::new(".defmac(R, (), (%R)) %R");
+        a.set_max_depth(3);
+        let mut e = a.assemble().unwrap_err();
+        for _ in 0..3 {
+            match e {
+                AvcErr::InExpansion(_, _, inner) => e = *inner,
+                _ => panic!("{}", e)
+            }
+        }
+        assert_eq!(e, AvcErr::MacroDepth(3));
+        // errors after pass 1 are traced too
+        let mut a = Assembler::new(".defmac(J, (), (NOP\n LIT ^nowhere JMP)) .defmac(K, (), (%J)) NOP %K");
+        assert_eq!(a.assemble(), Err(AvcErr::OnLine(1, Box::new(AvcErr::InExpansion("%K".into(), 0, Box::new(
+            AvcErr::InExpansion("%J".into(), 1, Box::new(AvcErr::UndefinedLabel("nowhere".into())))
+        ))))));
+        let mut a = Assembler::new(".defmac(J, (l), (LIT ^$l JMP)) .lbl(x) .fill(200, 0)\n.lbl(y) %J(y)\n%J(x)");
+        assert_eq!(a.assemble(), Err(AvcErr::OnLine(2, Box::new(AvcErr::InExpansion("%J".into(), 0, Box::new(AvcErr::RelJumpTooLarge))))));
+        let mut a = Assembler::new(".defmac(L, (), (.lbl(x))) .rept(2, (%L))");
+        assert_eq!(a.assemble(), Err(AvcErr::OnLine(0, Box::new(AvcErr::InExpansion(".rept".into(), 0, Box::new(
+            AvcErr::InExpansion("%L".into(), 0, Box::new(AvcErr::DuplicateLabel("x".into())))
+        ))))));
+        let mut a = Assembler::new(".defmac(I, (), (NOP\n.if(1) NOP)) %I");
+        assert_eq!(a.assemble(), Err(AvcErr::InExpansion("%I".into(), 1, Box::new(AvcErr::UnterminatedIf))));
+    }
 }
@@ -3,34 +3,150 @@ use crate::utils::*;
 #[derive(Debug, PartialEq)]
 pub struct AvcMacro {
     text: String,
-    args: Vec<String>
+    args: Vec<String>,
+    defaults: Vec<Option<String>>,
+    variadic: Option<String>
 }
 
 impl AvcMacro {
     pub fn new(mac: &str) -> Result<AvcMacro> {
         let segments = split_bracket_groups(mac, ',');
-        let args = segments[1];
-        let args = &args[1..args.len() - 1];
-        let args = args.split(',').map(|s| format!("${}", s.trim())).collect();
+        if segments.len() != 3 {
+            return Err(AvcErr::MalformedDirective(format!("defmac({})", mac)))
+        }
+        let args = unwrap_group(segments[1]);
+        let mut arg_names = Vec::new();
+        let mut defaults = Vec::new();
+        let mut variadic = None;
+        if !args.is_empty() {
+            for a in split_bracket_groups(args, ',') {
+                if variadic.is_some() { // variadic has to be last
+                    return Err(AvcErr::MalformedDirective(format!("defmac({})", mac)))
+                }
+                if let Some(name) = a.strip_suffix("...") {
+                    variadic = Some(format!("${}", name.trim()))
+                }
+                else if let Some((name, default)) = a.split_once('=') {
+                    arg_names.push(format!("${}", name.trim()));
+                    defaults.push(Some(unwrap_group(default.trim()).into()))
+                }
+                else {
+                    arg_names.push(format!("${}", a));
+                    defaults.push(None)
+                }
+            }
+        }
         let text = segments[2];
         let text = text[1..text.len() - 1].into();
 
         Ok(AvcMacro {
-            text, args
+            text, args: arg_names, defaults, variadic
         })
     }
-    pub fn expand(&self, args: Vec<&str>) -> String {
-        assert_eq!(args.len(), self.args.len()); // lazy
+    /// `id` must be unique to each expansion. it replaces `\@` and is used to make `?local` labels
+    pub fn expand(&self, args: Vec<&str>, id: usize) -> Result<String> {
+        if args.len() > self.args.len() && self.variadic.is_none() {
+            return Err(AvcErr::MacroArgCount(args.len()))
+        }
 
-        let mut expansion = self.text.clone();
-        for (arg, trig) in args.iter().zip(&self.args) {
-            expansion = expansion.replace(trig, arg)
+        let mut subs = Vec::new();
+        for (i, trig) in self.args.iter().enumerate() {
+            let arg = match args.get(i) {
+                Some(a) => String::from(*a),
+                None => self.defaults[i].clone().ok_or(AvcErr::MacroArgCount(args.len()))?
+            };
+            subs.push((trig.as_str(), arg))
+        }
+        let extra = args.get(self.args.len()..).unwrap_or_default();
+        if let Some(trig) = &self.variadic {
+            // requote anything that would get split up again
+            let joined = extra.iter()
+                .map(|a| if a.contains(',') { format!("{{{}}}", a) } else { String::from(*a) })
+                .collect::<Vec<_>>()
+                .join(", ");
+            subs.push((trig.as_str(), joined))
         }
+        subs.sort_by_key(|(trig, _)| std::cmp::Reverse(trig.len())); // so $a doesn't clobber $ab
 
-        expansion
+        let mut expansion = self.text.replace("$#", &extra.len().to_string());
+        for (trig, arg) in subs {
+            expansion = expansion.replace(trig, &arg)
+        }
+
+        Ok(localise(&expansion.replace("\\@", &id.to_string()), id))
     }
 }
 
+/// rename `?name` to `name?id` so labels don't collide between expansions
+fn localise(s: &str, id: usize) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut prev = ' ';
+    let mut local = false;
+    let mut in_string = false;
+    let mut escaped = false;
+    // whether each open bracket is a call, and the last char that isn't whitespace, to tell where strings start
+    let mut calls = Vec::new();
+    let mut last = ' ';
+    let mut in_char = false;
+    for c in s.chars() {
+        if local && !is_label_char(c) {
+            ret.push_str(&format!("?{}", id));
+            local = false
+        }
+        let last_solid = last;
+        if !c.is_whitespace() {
+            last = c
+        }
+        if in_char { // `'(` is just a char
+            in_char = false;
+            ret.push(c);
+            prev = c;
+            continue
+        }
+        in_char = c == '\'' && !in_string && starts_char(prev);
+        if c == '(' && !in_string {
+            calls.push(is_call(prev))
+        }
+        if c == ')' && !in_string {
+            calls.pop();
+        }
+        if in_string { // strings are left alone
+            if escaped {
+                escaped = false
+            }
+            else if c == '\\' {
+                escaped = true
+            }
+            else if c == '"' {
+                in_string = false
+            }
+            ret.push(c)
+        }
+        else if c == '"' && starts_string(last_solid, &calls) {
+            in_string = true;
+            ret.push(c)
+        }
+        else if c == '?' && starts_name(prev) {
+            local = true
+        }
+        else {
+            ret.push(c)
+        }
+        prev = c
+    }
+    if local {
+        ret.push_str(&format!("?{}", id))
+    }
+    ret
+}
+/// whether a name can start after `prev`, ie. it's the start of a word or argument, or comes after a rune
+fn starts_name(prev: char) -> bool {
+    prev.is_whitespace() || matches!(prev, '(' | ',' | '^' | '@' | ';' | '-' | '=' | ':')
+}
+fn is_label_char(c: char) -> bool {
+    !(c.is_whitespace() || c == ')' || c == '(' || c == ',')
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -38,14 +154,35 @@ mod tests {
     #[test]
     fn mac_create() {
         let m = AvcMacro::new("aaa, (aaa), (LIT #01 CLC ADC)");
-        let m_correct = AvcMacro { text: "LIT #01 CLC ADC".into(), args: vec!["$aaa".into()] };
+        let m_correct = AvcMacro { text: "LIT #01 CLC ADC".into(), args: vec!["$aaa".into()], defaults: vec![None], variadic: None };
         assert_eq!(m, Ok(m_correct))
     }
 
     #[test]
     fn mac_expand_1() {
         let m = AvcMacro::new("aaa, (arg), (TEST $arg TEST)").unwrap();
-        let exp = m.expand(vec!["beans"]);
-        assert_eq!(exp, String::from("TEST beans TEST"))
+        let exp = m.expand(vec!["beans"], 0);
+        assert_eq!(exp, Ok(String::from("TEST beans TEST")))
+    }
+
+    #[test]
+    fn mac_expand_local() {
+        let m = AvcMacro::new("aaa, (), (.lbl(?loop) LIT ^?loop JMP '? .lbl(x\\@))").unwrap();
+        assert_eq!(m.expand(vec![], 3), Ok(String::from(".lbl(loop?3) LIT ^loop?3 JMP '? .lbl(x3)")));
+        assert_eq!(m.expand(vec![], 4), Ok(String::from(".lbl(loop?4) LIT ^loop?4 JMP '? .lbl(x4)")));
+        let m = AvcMacro::new(r#"aaa, (), (.sz("a? \"b?") LIT '" .lbl(?c))"#).unwrap();
+        assert_eq!(m.expand(vec![], 1), Ok(String::from(r#".sz("a? \"b?") LIT '" .lbl(c?1)"#)));
+        let m = AvcMacro::new("aaa, (), (.s(why?) ;?a ,?b -?c ;r:?d)").unwrap();
+        assert_eq!(m.expand(vec![], 2), Ok(String::from(".s(why?) ;a?2 ,b?2 -c?2 ;r:d?2")))
+    }
+
+    #[test]
+    fn mac_default_variadic() {
+        let m = AvcMacro::new("aaa, (a, ab=02, rest...), ($a $ab $# ($rest))").unwrap();
+        assert_eq!(m.expand(vec!["01"], 0), Ok(String::from("01 02 0 ()")));
+        assert_eq!(m.expand(vec!["01", "03", "x", "y, z"], 0), Ok(String::from("01 03 2 (x, {y, z})")));
+        assert_eq!(m.expand(vec![], 0), Err(AvcErr::MacroArgCount(0)));
+        let m = AvcMacro::new("aaa, (a), ($a)").unwrap();
+        assert_eq!(m.expand(vec!["1", "2"], 0), Err(AvcErr::MacroArgCount(2)))
     }
 }
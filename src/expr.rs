@@ -0,0 +1,338 @@
+use crate::utils::*;
+
+/// assemble-time arithmetic, used by `.if`, `.const` and friends
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Num(i64),
+    Name(String),
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// resolves a name to an offset and the base it's from, if it isn't absolute
+pub type RelocLookup<'a, T> = dyn Fn(&str) -> Option<(i64, Option<T>)> + 'a;
+
+// loosest first
+const PRECEDENCE: &[&[&str]] = &[
+    &["||"], &["&&"], &["|"], &["^"], &["&"],
+    &["==", "!="], &["<=", ">=", "<", ">"],
+    &["<<", ">>"], &["+", "-"], &["*", "/", "%"],
+];
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr> {
+        let tokens = lex(s)?;
+        let mut pos = 0;
+        let e = parse_binary(&tokens, &mut pos, 0).ok_or_else(|| AvcErr::BadExpr(s.into()))?;
+        if pos != tokens.len() {
+            return Err(AvcErr::BadExpr(s.into()))
+        }
+        Ok(e)
+    }
+    /// call `f` on every name, so they can be resolved or renamed
+    pub fn names_mut(&mut self, f: &mut dyn FnMut(&mut String)) {
+        match self {
+            Expr::Num(_) => {}
+            Expr::Name(n) => f(n),
+            Expr::Unary(_, e) => e.names_mut(f),
+            Expr::Binary(_, l, r) => {
+                l.names_mut(f);
+                r.names_mut(f)
+            }
+        }
+    }
+    /// `lookup` resolves names to values, returning `None` if they don't exist
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64> {
+        Ok(match self {
+            Expr::Num(n) => *n,
+            Expr::Name(n) => lookup(n).ok_or_else(|| AvcErr::UndefinedSymbol(n.clone()))?,
+            Expr::Unary(op, e) => unary(*op, e.eval(lookup)?),
+            Expr::Binary(op, l, r) => binary(op, l.eval(lookup)?, r.eval(lookup)?)?
+        })
+    }
+    /// like `eval`, but names can also be an offset from a base that isn't known yet, like the start of a section.
+    /// the result can only depend on one base, which is returned with the offset from it
+    pub fn eval_reloc<T: PartialEq + Clone>(&self, lookup: &RelocLookup<T>) -> Result<(i64, Option<T>)> {
+        let (value, mut bases) = self.linear(lookup)?;
+        bases.retain(|(_, n)| *n != 0);
+        match &bases[..] {
+            [] => Ok((value, None)),
+            [(base, 1)] => Ok((value, Some(base.clone()))),
+            _ => Err(AvcErr::NotRelocatable(self.to_string()))
+        }
+    }
+    /// a constant plus a multiple of each base
+    fn linear<T: PartialEq + Clone>(&self, lookup: &RelocLookup<T>) -> Result<(i64, Vec<(T, i64)>)> {
+        Ok(match self {
+            Expr::Num(n) => (*n, Vec::new()),
+            Expr::Name(n) => {
+                let (value, base) = lookup(n).ok_or_else(|| AvcErr::UndefinedSymbol(n.clone()))?;
+                (value, base.into_iter().map(|b| (b, 1)).collect())
+            }
+            Expr::Unary(op, e) => {
+                let (v, bases) = e.linear(lookup)?;
+                match op {
+                    '-' => (v.wrapping_neg(), bases.into_iter().map(|(b, n)| (b, -n)).collect()),
+                    _ if bases.is_empty() => (unary(*op, v), bases),
+                    _ => return Err(AvcErr::NotRelocatable(self.to_string()))
+                }
+            }
+            Expr::Binary(op, l, r) => {
+                let (l, mut lb) = l.linear(lookup)?;
+                let (r, rb) = r.linear(lookup)?;
+                match *op {
+                    "+" | "-" => {
+                        let sign = if *op == "+" { 1 } else { -1 };
+                        for (b, n) in rb {
+                            match lb.iter_mut().find(|(lb, _)| *lb == b) {
+                                Some((_, ln)) => *ln += sign * n,
+                                None => lb.push((b, sign * n))
+                            }
+                        }
+                        (binary(op, l, r)?, lb)
+                    }
+                    "*" if lb.is_empty() => (l.wrapping_mul(r), rb.into_iter().map(|(b, n)| (b, n * l)).collect()),
+                    "*" if rb.is_empty() => (l.wrapping_mul(r), lb.into_iter().map(|(b, n)| (b, n * r)).collect()),
+                    _ if lb.is_empty() && rb.is_empty() => (binary(op, l, r)?, lb),
+                    _ => return Err(AvcErr::NotRelocatable(self.to_string()))
+                }
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Num(n) => write!(f, "{}", n),
+            Expr::Name(n) => write!(f, "{}", n),
+            Expr::Unary(op, e) if matches!(**e, Expr::Binary(..)) => write!(f, "{}({})", op, e),
+            Expr::Unary(op, e) => write!(f, "{}{}", op, e),
+            Expr::Binary(op, l, r) => {
+                for (i, e) in [l, r].into_iter().enumerate() {
+                    if i == 1 {
+                        write!(f, " {} ", op)?
+                    }
+                    match **e {
+                        Expr::Binary(..) => write!(f, "({})", e)?,
+                        _ => write!(f, "{}", e)?
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn unary(op: char, v: i64) -> i64 {
+    match op {
+        '-' => v.wrapping_neg(),
+        '~' => !v,
+        _ => (v == 0) as i64 // !
+    }
+}
+
+fn binary(op: &str, l: i64, r: i64) -> Result<i64> {
+    Ok(match op {
+        "||" => (l != 0 || r != 0) as i64,
+        "&&" => (l != 0 && r != 0) as i64,
+        "|" => l | r,
+        "^" => l ^ r,
+        "&" => l & r,
+        "==" => (l == r) as i64,
+        "!=" => (l != r) as i64,
+        "<=" => (l <= r) as i64,
+        ">=" => (l >= r) as i64,
+        "<" => (l < r) as i64,
+        ">" => (l > r) as i64,
+        "<<" => l.wrapping_shl(r as u32),
+        ">>" => l.wrapping_shr(r as u32),
+        "+" => l.wrapping_add(r),
+        "-" => l.wrapping_sub(r),
+        "*" => l.wrapping_mul(r),
+        _ => { // / and %
+            if r == 0 {
+                return Err(AvcErr::DivideByZero)
+            }
+            // only MIN / -1 overflows
+            let v = if op == "/" { l.checked_div(r) } else { l.checked_rem(r) };
+            v.ok_or(AvcErr::ValueTooLarge(l))?
+        }
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum ExprToken {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+const OPS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "~", "!",
+];
+
+pub fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '/' || c == '?'
+}
+
+fn lex(s: &str) -> Result<Vec<ExprToken>> {
+    let mut ret = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '(' {
+            ret.push(ExprToken::Open);
+            rest = &rest[1..]
+        }
+        else if c == ')' {
+            ret.push(ExprToken::Close);
+            rest = &rest[1..]
+        }
+        else if c == '\'' { // ascii char
+            let ch = rest[1..].chars().next().ok_or_else(|| AvcErr::BadExpr(s.into()))?;
+            if !ch.is_ascii() {
+                return Err(AvcErr::MultibyteChar(ch))
+            }
+            ret.push(ExprToken::Num(ch as i64));
+            rest = &rest[2..]
+        }
+        else if c == '#' || c.is_ascii_digit() {
+            let end = rest[1..].find(|c: char| !c.is_ascii_alphanumeric()).map(|i| i + 1).unwrap_or(rest.len());
+            ret.push(ExprToken::Num(parse_num(&rest[..end])?));
+            rest = &rest[end..]
+        }
+        else if c == '$' { // current address
+            ret.push(ExprToken::Name("$".into()));
+            rest = &rest[1..]
+        }
+        else if is_name_start(c) || (c == '&' && rest[1..].starts_with(is_name_start) && !after_operand(&ret)) { // &sublabel
+            let end = rest[1..].find(|c| !is_name_char(c)).map(|i| i + 1).unwrap_or(rest.len());
+            ret.push(ExprToken::Name(rest[..end].into()));
+            rest = &rest[end..]
+        }
+        else {
+            let op = OPS.iter().find(|op| rest.starts_with(**op)).ok_or_else(|| AvcErr::BadExpr(s.into()))?;
+            ret.push(ExprToken::Op(op));
+            rest = &rest[op.len()..]
+        }
+        rest = rest.trim_start()
+    }
+    Ok(ret)
+}
+
+/// whether the last token can be the left side of a binary operator
+fn after_operand(tokens: &[ExprToken]) -> bool {
+    matches!(tokens.last(), Some(ExprToken::Num(_) | ExprToken::Name(_) | ExprToken::Close))
+}
+
+/// decimal, or hex with `#` or `0x`, or binary with `0b`
+pub fn parse_num(s: &str) -> Result<i64> {
+    let bad = || AvcErr::BadInt(s.into());
+    if let Some(hex) = s.strip_prefix('#').or_else(|| s.strip_prefix("0x")) {
+        i64::from_str_radix(hex, 16).map_err(|_| bad())
+    }
+    else if let Some(bin) = s.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).map_err(|_| bad())
+    }
+    else {
+        s.parse().map_err(|_| bad())
+    }
+}
+
+fn parse_binary(tokens: &[ExprToken], pos: &mut usize, level: usize) -> Option<Expr> {
+    if level == PRECEDENCE.len() {
+        return parse_unary(tokens, pos)
+    }
+    let mut lhs = parse_binary(tokens, pos, level + 1)?;
+    while let Some(ExprToken::Op(op)) = tokens.get(*pos) {
+        if !PRECEDENCE[level].contains(op) {
+            break
+        }
+        *pos += 1;
+        let rhs = parse_binary(tokens, pos, level + 1)?;
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+    Some(lhs)
+}
+
+fn parse_unary(tokens: &[ExprToken], pos: &mut usize) -> Option<Expr> {
+    let t = tokens.get(*pos)?;
+    *pos += 1;
+    match t {
+        ExprToken::Num(n) => Some(Expr::Num(*n)),
+        ExprToken::Name(n) => Some(Expr::Name(n.clone())),
+        ExprToken::Op(op) if ["-", "~", "!"].contains(op) => {
+            let e = parse_unary(tokens, pos)?;
+            Some(Expr::Unary(op.chars().next().unwrap(), Box::new(e)))
+        }
+        ExprToken::Open => {
+            let e = parse_binary(tokens, pos, 0)?;
+            if tokens.get(*pos) != Some(&ExprToken::Close) {
+                return None
+            }
+            *pos += 1;
+            Some(e)
+        }
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str) -> Result<i64> {
+        Expr::parse(s)?.eval(&|n| match n {
+            "X" => Some(3),
+            "&y" => Some(4),
+            "$" => Some(0x300),
+            _ => None
+        })
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("#ff & ~0x0f"), Ok(0xf0));
+        assert_eq!(eval("-X + 0b100"), Ok(1));
+        assert_eq!(eval("X == 3 && !(X > 4)"), Ok(1));
+        assert_eq!(eval("1 << 4 | 'a"), Ok(0x71));
+        assert_eq!(eval("$ + &y & X"), Ok(0));
+        assert_eq!(eval("$ + &y - X"), Ok(0x301));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval("Y"), Err(AvcErr::UndefinedSymbol("Y".into())));
+        assert_eq!(eval("1 / 0"), Err(AvcErr::DivideByZero));
+        assert_eq!(eval("(0 - 9223372036854775807 - 1) / -1"), Err(AvcErr::ValueTooLarge(i64::MIN)));
+        assert_eq!(eval("(0 - 9223372036854775807 - 1) % -1"), Err(AvcErr::ValueTooLarge(i64::MIN)));
+        assert_eq!(eval("1 +"), Err(AvcErr::BadExpr("1 +".into())));
+        assert_eq!(eval("(1"), Err(AvcErr::BadExpr("(1".into())));
+        assert_eq!(eval("12g"), Err(AvcErr::BadInt("12g".into())));
+    }
+
+    #[test]
+    fn relocation() {
+        let reloc = |s: &str| Expr::parse(s)?.eval_reloc(&|n| match n {
+            "a" => Some((2, Some("text"))),
+            "b" => Some((6, Some("text"))),
+            "c" => Some((1, Some("data"))),
+            "N" => Some((3, None)),
+            _ => None
+        });
+        assert_eq!(reloc("a + N * 2"), Ok((8, Some("text"))));
+        assert_eq!(reloc("b - a"), Ok((4, None)));
+        assert_eq!(reloc("(c - a) + b"), Ok((5, Some("data"))));
+        assert_eq!(reloc("-N + 2 * c - c"), Ok((-2, Some("data"))));
+        assert_eq!(reloc("a + c"), Err(AvcErr::NotRelocatable("a + c".into())));
+        assert_eq!(reloc("(a >> 8) & #ff"), Err(AvcErr::NotRelocatable("a >> 8".into())));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::utils::*;
+use crate::object::*;
+use crate::script::*;
+use std::collections::HashMap;
+
+/// puts objects together into a rom
+#[derive(Default)]
+pub struct Linker {
+    objects: Vec<Object>,
+    /// members are only linked if something needs them
+    archives: Vec<Archive>,
+    /// what gaps in the rom are filled with, unless the script says otherwise
+    fill: u8,
+    script: Option<Script>,
+}
+
+/// where everything goes
+struct Layout<'a> {
+    /// the start of each object's sections
+    bases: Vec<HashMap<&'a str, usize>>,
+    /// start, end and fill byte of sections that have one
+    fills: Vec<(usize, usize, u8)>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker::default()
+    }
+    pub fn add(&mut self, obj: Object) {
+        self.objects.push(obj)
+    }
+    pub fn add_archive(&mut self, archive: Archive) {
+        self.archives.push(archive)
+    }
+    pub fn set_fill(&mut self, fill: u8) {
+        self.fill = fill
+    }
+    pub fn set_script(&mut self, script: Script) {
+        self.script = Some(script)
+    }
+    pub fn link(&self) -> Result<Vec<u8>> {
+        let objects = self.select();
+        let script = match &self.script {
+            Some(s) => s.clone(),
+            None => default_script(&objects)
+        };
+        let fill = script.fill.unwrap_or(self.fill);
+        let Layout { bases, fills } = place(&objects, &script)?;
+
+        let mut symbols: HashMap<&str, i64> = HashMap::new();
+        for (obj, bases) in objects.iter().zip(&bases) {
+            for (name, section, value) in &obj.exports {
+                let base = match section {
+                    Some(s) => *bases.get(s.as_str()).ok_or_else(|| AvcErr::BadObject(format!("export {}", name)))? as i64,
+                    None => 0
+                };
+                if symbols.insert(name, base + value).is_some() {
+                    return Err(AvcErr::DuplicateLabel(name.clone()))
+                }
+            }
+        }
+
+        // rom header
+        let mut ret = vec![0x41, 0x56, 0x43, 0x00];
+        // fill sections that ask for it first, so their contents go on top
+        for (start, end, f) in fills {
+            let (start, end) = (start.max(0x0300) - 0x0300 + 4, end.max(0x0300) - 0x0300 + 4);
+            if end > ret.len() {
+                ret.resize(end, fill)
+            }
+            ret[start..end].fill(f)
+        }
+        for (obj, bases) in objects.iter().zip(&bases) {
+            for section in &obj.sections {
+                let base = bases[section.name.as_str()];
+                if section.bytes.is_empty() {
+                    continue
+                }
+                if base < 0x0300 {
+                    return Err(AvcErr::OpNotInCodeSpace)
+                }
+                for (i, b) in section.bytes.iter().enumerate() {
+                    set_vec_at(&mut ret, *b, base + i - 0x0300 + 4, fill)
+                }
+                for r in &section.relocs {
+                    let value = r.addend + match &r.target {
+                        Target::Section(s) => *bases.get(s.as_str()).ok_or_else(|| AvcErr::BadObject(format!("reloc .{}", s)))? as i64,
+                        Target::Symbol(s) => *symbols.get(s.as_str()).ok_or_else(|| AvcErr::UndefinedLabel(s.clone()))?,
+                        Target::Abs => 0
+                    };
+                    let pos = base + r.offset;
+                    write_value(&mut ret, value, &r.kind, pos + 4, pos - 0x0300 + 4, fill)?
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+    /// every object that was added, and the archive members that define symbols they need, and so on
+    fn select(&self) -> Vec<&Object> {
+        let mut objects: Vec<&Object> = self.objects.iter().collect();
+        let mut needed: Vec<&str> = objects.iter().flat_map(|o| &o.imports).map(String::as_str).collect();
+        needed.retain(|n| !objects.iter().any(|o| o.exports.iter().any(|(e, ..)| e == n)));
+        let mut used = vec![false; self.archives.iter().map(|a| a.members.len()).sum()];
+        loop {
+            let mut pulled = false;
+            let members = self.archives.iter().flat_map(|a| &a.members);
+            for ((name, obj), used) in members.zip(&mut used) {
+                if *used || !obj.exports.iter().any(|(e, ..)| needed.contains(&e.as_str())) {
+                    continue
+                }
+                println!("linking {}", name);
+                *used = true;
+                pulled = true;
+                objects.push(obj);
+                needed.retain(|n| !obj.exports.iter().any(|(e, ..)| e == n));
+                needed.extend(obj.imports.iter().map(String::as_str).filter(|n| {
+                    !objects.iter().any(|o| o.exports.iter().any(|(e, ..)| e == n))
+                }))
+            }
+            if !pulled {
+                return objects
+            }
+        }
+    }
+}
+
+/// without a script, everything without a fixed address goes one after another from 0x0300. sections
+/// with the same name go together, with `code` first and the rest in the order they first appear
+fn default_script(objects: &[&Object]) -> Script {
+    let mut names = vec!["code"];
+    for section in objects.iter().flat_map(|o| &o.sections) {
+        if section.addr.is_none() && !names.contains(&section.name.as_str()) {
+            names.push(&section.name)
+        }
+    }
+    Script {
+        fill: None,
+        regions: vec![Region { name: "rom".into(), start: 0x0300, size: 0xfd00 }],
+        sections: names.into_iter().map(|n| Placement {
+            section: n.into(), region: "rom".into(), at: None, align: 1, max: None, fill: None
+        }).collect()
+    }
+}
+/// decide where every section goes. sections with a fixed address always go there
+fn place<'a>(objects: &[&'a Object], script: &Script) -> Result<Layout<'a>> {
+    let mut bases = vec![HashMap::new(); objects.len()];
+    let mut fills = Vec::new();
+    // the name, start and end of everything placed, to check nothing overlaps
+    let mut ranges: Vec<(&str, usize, usize)> = Vec::new();
+    // where the next section in each region goes
+    let mut next: HashMap<&str, usize> = script.regions.iter().map(|r| (r.name.as_str(), r.start)).collect();
+    for p in &script.sections {
+        let region = script.regions.iter().find(|r| r.name == p.region).ok_or_else(|| AvcErr::UndefinedRegion(p.region.clone()))?;
+        let mut counter = next[region.name.as_str()];
+        let start = counter;
+        if let Some(at) = p.at {
+            if at < region.start {
+                return Err(AvcErr::RegionOverflow(p.section.clone(), region.name.clone()))
+            }
+            if at < counter {
+                return Err(AvcErr::SectionOverlap(p.section.clone()))
+            }
+            counter = at
+        }
+        let mut first = None;
+        for (i, obj) in objects.iter().enumerate() {
+            for section in obj.sections.iter().filter(|s| s.name == p.section && s.addr.is_none()) {
+                counter = align(counter, section.align.max(p.align));
+                first.get_or_insert(counter);
+                bases[i].insert(section.name.as_str(), counter);
+                counter += section.size
+            }
+        }
+        let size = counter - first.unwrap_or(counter);
+        if let Some(max) = p.max {
+            if size > max {
+                return Err(AvcErr::SectionTooBig(p.section.clone(), size, max))
+            }
+        }
+        if counter > region.start + region.size {
+            return Err(AvcErr::RegionOverflow(p.section.clone(), region.name.clone()))
+        }
+        if let Some(f) = p.fill {
+            fills.push((start, counter, f))
+        }
+        ranges.push((&p.section, first.unwrap_or(counter), counter));
+        next.insert(&region.name, counter);
+    }
+    for (i, obj) in objects.iter().enumerate() {
+        for section in &obj.sections {
+            match section.addr {
+                Some(addr) => {
+                    bases[i].insert(section.name.as_str(), addr as usize);
+                    ranges.push((&section.name, addr as usize, addr as usize + section.size))
+                }
+                None if !bases[i].contains_key(section.name.as_str()) => {
+                    return Err(AvcErr::UnplacedSection(section.name.clone()))
+                }
+                None => {}
+            }
+        }
+    }
+    ranges.retain(|(_, start, end)| start < end);
+    for (i, (name, start, end)) in ranges.iter().enumerate() {
+        if ranges[..i].iter().any(|(_, s, e)| start < e && s < end) {
+            return Err(AvcErr::SectionOverlap(name.to_string()))
+        }
+    }
+    Ok(Layout { bases, fills })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn link(srcs: &[&str]) -> Result<Vec<u8>> {
+        link_with(srcs, None)
+    }
+    fn link_with(srcs: &[&str], script: Option<&str>) -> Result<Vec<u8>> {
+        let mut l = Linker::new();
+        if let Some(script) = script {
+            l.set_script(Script::parse(script)?)
+        }
+        for src in srcs {
+            // go through the text format too
+            let obj = Assembler::new(src).assemble_object()?.write();
+            l.add(Object::parse(&obj)?)
+        }
+        l.link()
+    }
+
+    #[test]
+    fn objects() {
+        let mut a = Assembler::new(".lbl(x) ;y LIT ^x JMP .lbl(&l) .relcall(print) .absc(x/l + 1) .section(data) .lbl(y) .sz(\"hi\")");
+        let obj = a.assemble_object().unwrap();
+        assert_eq!(obj.sections[0], ObjSection {
+            name: "code".into(), addr: None, align: 1, size: 9,
+            bytes: vec![0xa0, 0, 0, 0x80, 0xf7, 0x0a, 0, 0, 0],
+            relocs: vec![
+                Reloc { offset: 1, kind: LblKind::Abs, target: Target::Section("data".into()), addend: 0 },
+                Reloc { offset: 6, kind: LblKind::Rel, target: Target::Symbol("print".into()), addend: 0 },
+                Reloc { offset: 7, kind: LblKind::Abs, target: Target::Section("code".into()), addend: 7 },
+            ]
+        });
+        assert_eq!(obj.imports, vec![String::from("print")]);
+        let mut a = Assembler::new(".abspad(0400) NOP");
+        assert_eq!(a.assemble_object(), Err(AvcErr::NotRelocatable(".abspad(0400)".into())));
+        let mut a = Assembler::new(".lbl(a) .section(data) .lbl(b) .section(code) .absc(a + b)");
+        assert_eq!(a.assemble_object(), Err(AvcErr::NotRelocatable("a + b".into())));
+    }
+
+    #[test]
+    fn same_as_assembling() {
+        let main = ".var(ptr, 2) .export(main, ptr)
+            .lbl(main) ;msg JSR2 LIT -ptr LDZ2 .absc(print + 2)
+            .section(data) .lbl(msg) .sz(\"hi\")
+            .section(vectors, 0380) .absc(main)";
+        let lib = ".export(print)
+            .lbl(print) NOP .lbl(&loop) LIT ^main JMP LIT ^&loop JMP .dw(msg2, ptr)
+            .section(data) .lbl(msg2) .sz(\"lo\")";
+        // exports don't do anything when assembling normally
+        let whole = format!("{} .section(code) {}", main, lib.replace(".export(print)", ""));
+        let mut a = Assembler::new(&whole);
+        assert_eq!(link(&[main, lib]), a.assemble());
+        assert_eq!(link(&[main]), Err(AvcErr::UndefinedLabel("print".into())));
+        assert_eq!(link(&[lib, lib]), Err(AvcErr::DuplicateLabel("print".into())));
+    }
+
+    #[test]
+    fn scripts() {
+        let srcs = [
+            ".section(data) .lbl(msg) .sz(\"hi\") .section(code) ;msg .section(bss) .lbl(buf) .res(2)",
+            ".section(data) .db(1) .res(1) .section(code) NOP .section(bss) .lbl(buf2) .res(1) .section(code) ;buf2 .section(vectors, 031c) .db(2)",
+        ];
+        let script = "fill ee
+            region rom 0300 0020
+            region ram 0200 0003
+            section code rom
+            section data rom at 0310 align 4 fill 00
+            section bss ram max 4";
+        assert_eq!(link_with(&srcs, Some(script)), Ok(vec![
+            0x41, 0x56, 0x43, 0x00,
+            0xa0, 0x03, 0x10, 0x00, 0xa0, 0x02, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            b'h', b'i', 0, 0, 1, 0, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 2
+        ]));
+        let small = script.replace("ram 0200 0003", "ram 0200 0002");
+        assert_eq!(link_with(&srcs, Some(&small)), Err(AvcErr::RegionOverflow("bss".into(), "ram".into())));
+        let small = script.replace("max 4", "max 2");
+        assert_eq!(link_with(&srcs, Some(&small)), Err(AvcErr::SectionTooBig("bss".into(), 3, 2)));
+        let overlap = script.replace("at 0310", "at 0302");
+        assert_eq!(link_with(&srcs, Some(&overlap)), Err(AvcErr::SectionOverlap("data".into())));
+        let missing = script.replace("section bss ram max 4", "");
+        assert_eq!(link_with(&srcs, Some(&missing)), Err(AvcErr::UnplacedSection("bss".into())));
+        // fixed sections can't land on top of anything else
+        let vectors = ".section(vectors, 0380) .absc(0)";
+        assert_eq!(link(&[vectors, vectors]), Err(AvcErr::SectionOverlap("vectors".into())));
+        assert_eq!(link(&[vectors, ".fill(200, 1)"]), Err(AvcErr::SectionOverlap("vectors".into())));
+    }
+
+    #[test]
+    fn archives() {
+        let obj = |src: &str| Assembler::new(src).assemble_object().unwrap();
+        let main = ".lbl(main) ;puts JSR2 ;square JSR2";
+        let lib = [
+            ("puts.avco", ".export(puts) .lbl(puts) ;putc JSR2 JMP2r"),
+            ("putc.avco", ".export(putc) .lbl(putc) LIT2 #ff #09 STA JMP2r"),
+            ("unused.avco", ".export(unused) .lbl(unused) ;missing JSR2"),
+            ("square.avco", ".export(square) .lbl(square) DUP MUL JMP2r"),
+            ("square2.avco", ".export(square) .lbl(square) NOP"),
+        ];
+        let mut l = Linker::new();
+        l.add(obj(main));
+        l.add_archive(Archive { members: lib.iter().map(|(n, src)| (n.to_string(), obj(src))).collect() });
+        // unused members are left out, and the first to define something wins
+        let whole = format!("{} {} {} {}", main, lib[0].1, lib[1].1, lib[3].1);
+        assert_eq!(l.link(), Assembler::new(&whole).assemble());
+    }
+}
@@ -1,8 +1,16 @@
 mod utils;
 mod assembler;
 mod avcmacro;
+mod expr;
+mod object;
+mod linker;
+mod script;
 
 use assembler::Assembler;
+use expr::Expr;
+use object::{Object, Archive};
+use linker::Linker;
+use script::Script;
 use std::env::args;
 use std::process::exit;
 use std::fs::{read_to_string, write};
@@ -15,17 +23,161 @@ fn main() {
 }
 
 fn run() -> Result<(), i32> {
-    let args: Vec<String> = args().collect();
-    let (in_file, out_file) = match args.len() {
-        0 | 1 => return Err(2),
-        2 => (args[1].as_str(), "out.avcr"),
-        _ => (args[1].as_str(), args[2].as_str()),
-    };
+    let mut files = Vec::new();
+    let mut max_depth = None;
+    let mut preprocess = false;
+    let mut defines = Vec::new();
+    let mut fill = None;
+    let mut sym_file = None;
+    let mut object = false;
+    let mut strip = false;
+    let mut optimise = false;
+    let mut args = args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("link") => {
+            args.next();
+            return link(args)
+        }
+        Some("archive") => {
+            args.next();
+            return archive(args)
+        }
+        _ => {}
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-depth" => {
+                let depth = args.next().ok_or(2)?;
+                max_depth = Some(depth.parse::<usize>().map_err(|_| 2)?)
+            }
+            "--fill" => {
+                let byte = args.next().ok_or(2)?;
+                fill = Some(u8::from_str_radix(&byte, 16).map_err(|_| 2)?)
+            }
+            "--sym" => sym_file = Some(args.next().ok_or(2)?),
+            "-E" => preprocess = true,
+            "-c" => object = true,
+            "--strip" => strip = true,
+            "-O" => optimise = true,
+            "-D" => defines.push(args.next().ok_or(2)?),
+            _ if arg.starts_with("-D") => defines.push(arg[2..].into()),
+            _ => files.push(arg)
+        }
+    }
+    let in_file = files.first().ok_or(2)?;
     let code = read_to_string(in_file).map_err(|_| 1)?;
     let mut asm = Assembler::new(&code);
-    let rom = asm.assemble().unwrap();
+    if let Some(depth) = max_depth {
+        asm.set_max_depth(depth)
+    }
+    if let Some(fill) = fill {
+        asm.set_fill(fill)
+    }
+    asm.set_strip(strip);
+    asm.set_optimise(optimise);
+    for d in defines {
+        // -D NAME defines NAME as 1
+        let (name, value) = d.split_once('=').unwrap_or((&d, "1"));
+        let value = Expr::parse(value).and_then(|e| e.eval(&|_| None)).map_err(|e| {
+            println!("error in -D{}: {}", d, e);
+            2
+        })?;
+        asm.define(name, value)
+    }
+    if preprocess {
+        let src = asm.preprocess().map_err(|e| {
+            println!("error: {}", e);
+            1
+        })?;
+        match files.get(1) {
+            Some(out_file) => write(out_file, src).map_err(|_| 1)?,
+            None => print!("{}", src)
+        }
+        return Ok(())
+    }
+    if object {
+        let out_file = files.get(1).map(String::as_str).unwrap_or("out.avco");
+        let obj = asm.assemble_object().map_err(|e| {
+            println!("error: {}", e);
+            1
+        })?;
+        println!("assembly finished!");
+        return write(out_file, obj.write()).map_err(|_| 1)
+    }
+    let out_file = files.get(1).map(String::as_str).unwrap_or("out.avcr");
+    let rom = asm.assemble().map_err(|e| {
+        println!("error: {}", e);
+        1
+    })?;
     println!("assembly finished!");
+    if strip {
+        println!("stripped {} unused bytes", asm.stripped())
+    }
+    if optimise {
+        println!("made {} optimisations", asm.rewrites().len())
+    }
     write(out_file, rom).map_err(|_| 1)?;
+    if let Some(sym_file) = sym_file {
+        write(sym_file, asm.symbols()).map_err(|_| 1)?
+    }
 
     Ok(())
 }
+
+/// `a2asm link [-o OUTFILE] [-T SCRIPT] [--fill XX] OBJECTS...`, where objects can also be archives
+fn link(mut args: impl Iterator<Item = String>) -> Result<(), i32> {
+    let mut linker = Linker::new();
+    let mut out_file = String::from("out.avcr");
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out_file = args.next().ok_or(2)?,
+            "-T" => {
+                let script = read_to_string(args.next().ok_or(2)?).map_err(|_| 1)?;
+                linker.set_script(Script::parse(&script).map_err(|e| {
+                    println!("error: {}", e);
+                    1
+                })?)
+            }
+            "--fill" => {
+                let byte = args.next().ok_or(2)?;
+                linker.set_fill(u8::from_str_radix(&byte, 16).map_err(|_| 2)?)
+            }
+            _ => {
+                let obj = read_to_string(&arg).map_err(|_| 1)?;
+                let res = if obj.starts_with("avca") {
+                    Archive::parse(&obj).map(|a| linker.add_archive(a))
+                }
+                else {
+                    Object::parse(&obj).map(|o| linker.add(o))
+                };
+                res.map_err(|e| {
+                    println!("error in {}: {}", arg, e);
+                    1
+                })?
+            }
+        }
+    }
+    let rom = linker.link().map_err(|e| {
+        println!("error: {}", e);
+        1
+    })?;
+    println!("linking finished!");
+    write(out_file, rom).map_err(|_| 1)
+}
+
+/// `a2asm archive OUTFILE OBJECTS...`
+fn archive(mut args: impl Iterator<Item = String>) -> Result<(), i32> {
+    let out_file = args.next().ok_or(2)?;
+    let mut archive = Archive::default();
+    for arg in args {
+        let obj = read_to_string(&arg).map_err(|_| 1)?;
+        let obj = Object::parse(&obj).map_err(|e| {
+            println!("error in {}: {}", arg, e);
+            1
+        })?;
+        // members are named after the file they came from
+        let name = std::path::Path::new(&arg).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or(arg);
+        archive.members.push((name, obj))
+    }
+    write(out_file, archive.write()).map_err(|_| 1)
+}
@@ -0,0 +1,263 @@
+use crate::utils::*;
+
+/// a relocatable object, as written by `a2asm -c`. sections don't have addresses yet, so anything that
+/// depends on one is left as a relocation for the linker to fill in
+#[derive(Debug, PartialEq, Default)]
+pub struct Object {
+    pub sections: Vec<ObjSection>,
+    /// symbols other objects can use, with the section they're in (`None` if absolute) and the offset into it
+    pub exports: Vec<(String, Option<String>, i64)>,
+    /// symbols this object uses but doesn't define
+    pub imports: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ObjSection {
+    pub name: String,
+    /// fixed address, if the section has one
+    pub addr: Option<u16>,
+    /// the start address has to be a multiple of this
+    pub align: u16,
+    /// size including any reserved space at the end, which `bytes` leaves out
+    pub size: usize,
+    pub bytes: Vec<u8>,
+    pub relocs: Vec<Reloc>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Reloc {
+    /// offset into the section to write the value at
+    pub offset: usize,
+    pub kind: LblKind,
+    pub target: Target,
+    pub addend: i64,
+}
+
+/// what a relocation is relative to
+#[derive(Debug, PartialEq, Clone)]
+pub enum Target {
+    /// the start of a section in the same object
+    Section(String),
+    /// a symbol exported by another object
+    Symbol(String),
+    /// nothing, but the position of a relative jump still isn't known
+    Abs,
+}
+
+// the format is text, one record per line:
+//
+// avco
+// section NAME ADDR|- ALIGN SIZE
+// bytes HEX...
+// reloc OFFSET abs|rel|zpg .SECTION|*|SYMBOL ADDEND
+// export NAME SECTION|- VALUE
+// import NAME
+//
+// bytes and relocs belong to the last section, and all numbers are in hex
+impl Object {
+    pub fn write(&self) -> String {
+        let mut ret = String::from("avco\n");
+        for s in &self.sections {
+            let addr = s.addr.map(|a| format!("{:04x}", a)).unwrap_or_else(|| "-".into());
+            ret.push_str(&format!("section {} {} {:x} {:04x}\n", s.name, addr, s.align, s.size));
+            if !s.bytes.is_empty() {
+                let bytes: String = s.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                ret.push_str(&format!("bytes {}\n", bytes))
+            }
+            for r in &s.relocs {
+                let target = match &r.target {
+                    Target::Section(s) => format!(".{}", s),
+                    Target::Symbol(s) => s.clone(),
+                    Target::Abs => "*".into()
+                };
+                ret.push_str(&format!("reloc {:04x} {} {} {}\n", r.offset, kind_name(r.kind), target, hex(r.addend)))
+            }
+        }
+        for (name, section, value) in &self.exports {
+            ret.push_str(&format!("export {} {} {}\n", name, section.as_deref().unwrap_or("-"), hex(*value)))
+        }
+        for name in &self.imports {
+            ret.push_str(&format!("import {}\n", name))
+        }
+        ret
+    }
+
+    pub fn parse(s: &str) -> Result<Object> {
+        let mut lines = s.lines();
+        if lines.next() != Some("avco") {
+            return Err(AvcErr::BadObject("missing avco header".into()))
+        }
+        let mut ret = Object::default();
+        for line in lines {
+            let bad = || AvcErr::BadObject(line.into());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [] => {}
+                ["section", name, addr, align, size] => {
+                    ret.sections.push(ObjSection {
+                        name: name.into(),
+                        addr: match addr {
+                            "-" => None,
+                            _ => Some(u16::from_str_radix(addr, 16).map_err(|_| bad())?)
+                        },
+                        align: u16::from_str_radix(align, 16).map_err(|_| bad())?,
+                        size: usize::from_str_radix(size, 16).map_err(|_| bad())?,
+                        bytes: Vec::new(),
+                        relocs: Vec::new()
+                    })
+                }
+                ["bytes", bytes] => {
+                    let section = ret.sections.last_mut().ok_or_else(bad)?;
+                    section.bytes = (0..bytes.len()).step_by(2)
+                        .map(|i| bytes.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                        .collect::<Option<_>>().ok_or_else(bad)?
+                }
+                ["reloc", offset, kind, target, addend] => {
+                    let kind = match kind {
+                        "abs" => LblKind::Abs,
+                        "rel" => LblKind::Rel,
+                        "zpg" => LblKind::Zpg,
+                        _ => return Err(bad())
+                    };
+                    let target = match target {
+                        "*" => Target::Abs,
+                        _ => match target.strip_prefix('.') {
+                            Some(s) => Target::Section(s.into()),
+                            None => Target::Symbol(target.into())
+                        }
+                    };
+                    let offset = usize::from_str_radix(offset, 16).map_err(|_| bad())?;
+                    let addend = parse_hex(addend).ok_or_else(bad)?;
+                    ret.sections.last_mut().ok_or_else(bad)?.relocs.push(Reloc { offset, kind, target, addend })
+                }
+                ["export", name, section, value] => {
+                    let section = if section == "-" { None } else { Some(section.into()) };
+                    ret.exports.push((name.into(), section, parse_hex(value).ok_or_else(bad)?))
+                }
+                ["import", name] => ret.imports.push(name.into()),
+                _ => return Err(bad())
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// a bundle of objects, which the linker only uses the parts of it needs
+#[derive(Debug, PartialEq, Default)]
+pub struct Archive {
+    pub members: Vec<(String, Object)>,
+}
+
+// `avca`, then `member NAME` before each object
+impl Archive {
+    pub fn write(&self) -> String {
+        let mut ret = String::from("avca\n");
+        for (name, obj) in &self.members {
+            ret.push_str(&format!("member {}\n{}", name, obj.write()))
+        }
+        ret
+    }
+
+    pub fn parse(s: &str) -> Result<Archive> {
+        let mut lines = s.lines();
+        if lines.next() != Some("avca") {
+            return Err(AvcErr::BadObject("missing avca header".into()))
+        }
+        let mut ret = Archive::default();
+        let mut member: Option<(&str, String)> = None;
+        for line in lines {
+            if let Some(name) = line.strip_prefix("member ") {
+                if let Some((name, obj)) = member.replace((name.trim(), String::new())) {
+                    ret.members.push((name.into(), Object::parse(&obj)?))
+                }
+            }
+            else {
+                let (_, obj) = member.as_mut().ok_or_else(|| AvcErr::BadObject(line.into()))?;
+                obj.push_str(line);
+                obj.push('\n')
+            }
+        }
+        if let Some((name, obj)) = member {
+            ret.members.push((name.into(), Object::parse(&obj)?))
+        }
+        Ok(ret)
+    }
+}
+
+fn kind_name(kind: LblKind) -> &'static str {
+    match kind {
+        LblKind::Abs => "abs",
+        LblKind::Rel => "rel",
+        LblKind::Zpg => "zpg"
+    }
+}
+
+/// hex with a sign if it's negative
+fn hex(v: i64) -> String {
+    if v < 0 {
+        format!("-{:x}", -v)
+    }
+    else {
+        format!("{:x}", v)
+    }
+}
+fn parse_hex(s: &str) -> Option<i64> {
+    match s.strip_prefix('-') {
+        Some(s) => i64::from_str_radix(s, 16).ok().map(|v| -v),
+        None => i64::from_str_radix(s, 16).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let obj = Object {
+            sections: vec![
+                ObjSection {
+                    name: "code".into(), addr: None, align: 1, size: 6, bytes: vec![0xa0, 0, 0, 0x2a, 0x80],
+                    relocs: vec![
+                        Reloc { offset: 1, kind: LblKind::Abs, target: Target::Section("data".into()), addend: 2 },
+                        Reloc { offset: 4, kind: LblKind::Rel, target: Target::Symbol("print".into()), addend: -1 },
+                    ]
+                },
+                ObjSection {
+                    name: "data".into(), addr: Some(0x400), align: 1, size: 0x10, bytes: Vec::new(),
+                    relocs: vec![Reloc { offset: 0, kind: LblKind::Zpg, target: Target::Abs, addend: 0x12 }]
+                },
+            ],
+            exports: vec![("main".into(), Some("code".into()), 0), ("ptr".into(), None, 0x10)],
+            imports: vec!["print".into()],
+        };
+        let text = obj.write();
+        assert_eq!(text, "avco
+section code - 1 0006
+bytes a000002a80
+reloc 0001 abs .data 2
+reloc 0004 rel print -1
+section data 0400 1 0010
+reloc 0000 zpg * 12
+export main code 0
+export ptr - 10
+import print
+");
+        assert_eq!(Object::parse(&text), Ok(obj));
+        assert_eq!(Object::parse("avco\nbytes 00"), Err(AvcErr::BadObject("bytes 00".into())));
+        assert_eq!(Object::parse("avco\nsection code - 1 0\nbytes 0"), Err(AvcErr::BadObject("bytes 0".into())));
+    }
+
+    #[test]
+    fn archives() {
+        let obj = |name: &str| Object {
+            exports: vec![(name.into(), None, 1)],
+            ..Default::default()
+        };
+        let ar = Archive { members: vec![("a.avco".into(), obj("a")), ("b.avco".into(), obj("b"))] };
+        let text = ar.write();
+        assert_eq!(text, "avca\nmember a.avco\navco\nexport a - 1\nmember b.avco\navco\nexport b - 1\n");
+        assert_eq!(Archive::parse(&text), Ok(ar));
+        assert_eq!(Archive::parse("avca\navco"), Err(AvcErr::BadObject("avco".into())));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::utils::*;
+
+/// a linker script, saying where sections go. for example
+///
+/// ```text
+/// region zp 0000 0100
+/// region rom 0300 fd00
+/// section code rom
+/// section data rom align 10 max 0400
+/// ```
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct Script {
+    /// what gaps in the rom are filled with
+    pub fill: Option<u8>,
+    pub regions: Vec<Region>,
+    /// sections in the order they're placed
+    pub sections: Vec<Placement>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Region {
+    pub name: String,
+    pub start: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Placement {
+    pub section: String,
+    pub region: String,
+    /// where in the region to start, instead of after the last section
+    pub at: Option<usize>,
+    pub align: u16,
+    pub max: Option<usize>,
+    /// fills any gaps in the section, and before it if it's aligned
+    pub fill: Option<u8>,
+}
+
+impl Script {
+    // one statement per line, with numbers in hex
+    pub fn parse(s: &str) -> Result<Script> {
+        let mut ret = Script::default();
+        for line in s.lines() {
+            let line = line.split_once("//").map(|(l, _)| l).unwrap_or(line);
+            let bad = || AvcErr::BadScript(line.trim().into());
+            let hex = |s: &str| usize::from_str_radix(s, 16).map_err(|_| bad());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [] => {}
+                ["fill", byte] => ret.fill = Some(u8::from_str_radix(byte, 16).map_err(|_| bad())?),
+                ["region", name, start, size] => {
+                    ret.regions.push(Region { name: name.into(), start: hex(start)?, size: hex(size)? })
+                }
+                ["section", section, region, ref options @ ..] => {
+                    let mut p = Placement { section: section.into(), region: region.into(), at: None, align: 1, max: None, fill: None };
+                    for option in options.chunks(2) {
+                        match option {
+                            ["at", addr] => p.at = Some(hex(addr)?),
+                            ["align", amt] => p.align = u16::from_str_radix(amt, 16).ok().filter(|a| *a != 0).ok_or_else(bad)?,
+                            ["max", size] => p.max = Some(hex(size)?),
+                            ["fill", byte] => p.fill = Some(u8::from_str_radix(byte, 16).map_err(|_| bad())?),
+                            _ => return Err(bad())
+                        }
+                    }
+                    if ret.sections.iter().any(|s| s.section == p.section) {
+                        return Err(bad())
+                    }
+                    ret.sections.push(p)
+                }
+                _ => return Err(bad())
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let s = Script::parse("fill ff // comment\n\nregion rom 0300 fd00\nsection code rom\nsection data rom at 0800 align 10 max 100 fill 00");
+        assert_eq!(s, Ok(Script {
+            fill: Some(0xff),
+            regions: vec![Region { name: "rom".into(), start: 0x300, size: 0xfd00 }],
+            sections: vec![
+                Placement { section: "code".into(), region: "rom".into(), at: None, align: 1, max: None, fill: None },
+                Placement { section: "data".into(), region: "rom".into(), at: Some(0x800), align: 0x10, max: Some(0x100), fill: Some(0) },
+            ]
+        }));
+        assert_eq!(Script::parse("section code rom at"), Err(AvcErr::BadScript("section code rom at".into())));
+        assert_eq!(Script::parse("region rom 0300"), Err(AvcErr::BadScript("region rom 0300".into())));
+        assert_eq!(Script::parse("section code rom align 0"), Err(AvcErr::BadScript("section code rom align 0".into())));
+    }
+}
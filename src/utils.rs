@@ -11,7 +11,7 @@ pub struct Tokeniser<'a> {
 // scrub over string until you find a valid split point
 // use a separate tokeniser iterator to allow easy changes later
 impl Tokeniser<'_> {
-    pub fn new(code: &str) -> Tokeniser {
+    pub fn new(code: &str) -> Tokeniser<'_> {
         Tokeniser {
             code,
             line: 0
@@ -33,13 +33,32 @@ impl<'a> Iterator for Tokeniser<'a> {
         let mut tstart = 0;
         let mut line_at_tstart = 0;
         let mut bracket_layers = 0;
+        let mut quote_layers = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut in_char = false;
+        // whether each open bracket is a call, and the chars before this one, for telling where strings start
+        let mut calls = Vec::new();
+        let mut prev = ' ';
+        let mut last = ' ';
         
         for (i, c) in self.code.char_indices() {
             //println!("{:?}, hc {}, slc {}, mlc {}", c, hit_comment, in_line_comment, in_multiline_comment);
             if c == '\n' { // ALWAYS count lines
                 self.line += 1
             }
-            if hit_comment && !in_multiline_comment { // iterate until matching `\` or newline
+            if in_string { // skip everything until the closing quote
+                if escaped {
+                    escaped = false
+                }
+                else if c == '\\' {
+                    escaped = true
+                }
+                else if c == '"' {
+                    in_string = false
+                }
+            }
+            else if hit_comment && !in_multiline_comment { // iterate until matching `\` or newline
                 if c == '/' {
                     in_line_comment = true
                 }
@@ -71,11 +90,32 @@ impl<'a> Iterator for Tokeniser<'a> {
                 }
             }
             else {
-                if c == '(' { // only count brackets when outside comments
-                    bracket_layers += 1
+                let (before, last_solid) = (prev, last);
+                prev = c;
+                if !c.is_whitespace() {
+                    last = c
+                }
+                if in_char && !c.is_whitespace() { // `'(` is just a char, not a bracket or quote
+                    in_char = false;
+                    continue
+                }
+                in_char = c == '\'' && starts_char(before);
+                if c == '"' && starts_string(last_solid, &calls) { // so `"word` still works
+                    in_string = true
+                }
+                if c == '{' && bracket_layers > 0 { // quotes hide brackets, so `%MAC({ ) })` is one token
+                    quote_layers += 1
+                }
+                if c == '}' && bracket_layers > 0 {
+                    quote_layers -= 1
+                }
+                if c == '(' && quote_layers == 0 { // only count brackets when outside comments
+                    bracket_layers += 1;
+                    calls.push(is_call(before))
                 }
-                if c == ')' {
-                    bracket_layers -= 1
+                if c == ')' && quote_layers == 0 {
+                    bracket_layers -= 1;
+                    calls.pop();
                 }
                 //println!("{:?} {}", c, bracket_layers);
                 if !hit_word { // iterate until word start
@@ -92,7 +132,7 @@ impl<'a> Iterator for Tokeniser<'a> {
                     }
                 }
                 else { // found word
-                    if (c.is_whitespace()) && bracket_layers == 0 { // end word
+                    if (c.is_whitespace()) && bracket_layers == 0 && quote_layers == 0 { // end word
                         if c == '\n' {
                             self.line -= 1
                         }
@@ -190,13 +230,14 @@ pub fn op_to_byte(op: &str) -> Result<u8> {
     Ok(b)
 }
 
-pub fn set_vec_at<T: Default>(v: &mut Vec<T>, val: T, idx: usize) {
+/// set `v[idx]`, filling any gap before it with `fill`
+pub fn set_vec_at<T: Clone>(v: &mut Vec<T>, val: T, idx: usize, fill: T) {
     let vlen = v.len();
     if idx == vlen {
         v.push(val)
     }
     else if idx > vlen {
-        v.resize_with(idx, || T::default());
+        v.resize(idx, fill);
         v.push(val)
     }
     else {
@@ -204,17 +245,77 @@ pub fn set_vec_at<T: Default>(v: &mut Vec<T>, val: T, idx: usize) {
     }
 }
 
+/// whether a `(` after `prev` starts the arguments to a directive or macro, rather than a group like `(LIT #01)`
+pub fn is_call(prev: char) -> bool {
+    !(prev.is_whitespace() || matches!(prev, '(' | ',' | '{'))
+}
+/// whether a `"` after `last`, the last char that isn't whitespace, starts a quoted string. strings are only
+/// arguments, like `.s("a b")` or `.db(1, "a")`, so `"word` is still the shorthand anywhere else
+pub fn starts_string(last: char, calls: &[bool]) -> bool {
+    calls.last() == Some(&true) && matches!(last, '(' | ',')
+}
+
+/// whether a `'` after `prev` is a char like `'a`, rather than part of a word like `a'`
+pub fn starts_char(prev: char) -> bool {
+    prev.is_whitespace() || matches!(prev, '(' | ',' | '{')
+}
+
+/// split on `split`, ignoring anything inside brackets or `{}` quotes
 pub fn split_bracket_groups(s: &str, split: char) -> Vec<&str> {
     let mut ret = Vec::new();
     let mut last_idx = 0;
     let mut bracket_layers = 0;
+    let mut quote_layers = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut in_char = false;
+    // `s` is the arguments to a call, so it can start with a string
+    let mut calls = vec![true];
+    let mut prev = ' ';
+    let mut last = '(';
     for (i, c) in s.char_indices() {
         //println!("{}", c);
-        if c == '(' {
-            bracket_layers += 1
+        let (before, last_solid) = (prev, last);
+        prev = c;
+        if !c.is_whitespace() {
+            last = c
+        }
+        if in_char { // `'(` is just a char
+            in_char = false
+        }
+        else if in_string {
+            if escaped {
+                escaped = false
+            }
+            else if c == '\\' {
+                escaped = true
+            }
+            else if c == '"' {
+                in_string = false
+            }
+        }
+        else if c == '\'' && starts_char(before) {
+            in_char = true
+        }
+        else if c == '"' && starts_string(last_solid, &calls) {
+            in_string = true
+        }
+        else if c == '{' {
+            quote_layers += 1
+        }
+        else if c == '}' {
+            quote_layers -= 1
+        }
+        else if quote_layers > 0 { // brackets don't count inside quotes
+            continue
+        }
+        else if c == '(' {
+            bracket_layers += 1;
+            calls.push(is_call(before))
         }
         else if c == ')' {
-            bracket_layers -= 1
+            bracket_layers -= 1;
+            calls.pop();
         }
         else if c == split && bracket_layers == 0 {
             // split here
@@ -226,6 +327,112 @@ pub fn split_bracket_groups(s: &str, split: char) -> Vec<&str> {
     ret
 }
 
+/// strip one layer of `()` or `{}` if it surrounds the whole string
+pub fn unwrap_group(s: &str) -> &str {
+    let (open, close) = match s.chars().next() {
+        Some('(') => ('(', ')'),
+        Some('{') => ('{', '}'),
+        _ => return s
+    };
+    let mut layers = 0;
+    for (i, c) in s.char_indices() {
+        if c == open {
+            layers += 1
+        }
+        else if c == close {
+            layers -= 1;
+            if layers == 0 {
+                return if i == s.len() - 1 {
+                    s[1..i].trim()
+                }
+                else { // closes before the end, eg. `(a) (b)`
+                    s
+                }
+            }
+        }
+    }
+    s
+}
+
+/// parse a quoted string with escapes into bytes
+pub fn parse_string(s: &str) -> Result<Vec<u8>> {
+    let inner = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).ok_or_else(|| AvcErr::BadString(s.into()))?;
+    let mut ret = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            ret.extend(c.encode_utf8(&mut buf).bytes());
+            continue
+        }
+        let b = match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16).map_err(|_| AvcErr::BadEscape(format!("\\x{}", hex)))?
+            }
+            Some(c) => return Err(AvcErr::BadEscape(format!("\\{}", c))),
+            None => return Err(AvcErr::BadString(s.into()))
+        };
+        ret.push(b)
+    }
+    Ok(ret)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LblKind {
+    Abs, Rel, Zpg
+}
+// round cur up to the next multiple of amt
+pub fn align(cur: usize, amt: u16) -> usize {
+    let amtu = amt as usize;
+    if cur.is_multiple_of(amtu) {
+        cur
+    }
+    else {
+        let muls = cur / amtu;
+        (muls + 1) * amtu
+    }
+}
+
+/// write a label address or expression value at `counter`
+pub fn write_value(rom: &mut Vec<u8>, value: i64, kind: &LblKind, counter: usize, counter_inner: usize, fill: u8) -> Result<()> {
+    match kind {
+        LblKind::Abs => {
+            let [hb, lb] = to_short(value)?.to_be_bytes();
+            set_vec_at(rom, hb, counter_inner, fill);
+            set_vec_at(rom, lb, counter_inner + 1, fill);
+        }
+        LblKind::Rel => {
+            // signed 8 bit
+            // if counter > addr, jump forwards ie. ctr - addr
+            // if addr > counter, jump back ie. (addr - ctr) * -1
+            // sub 1 to account for Things
+
+            let rel: i8 = ((value - (counter as i64)) - 1)
+                .try_into().map_err(|_| AvcErr::RelJumpTooLarge)?;
+            set_vec_at(rom, rel as u8, counter_inner, fill)
+        }
+        LblKind::Zpg => {
+            set_vec_at(rom, to_byte(value)?, counter_inner, fill)
+        }
+    }
+    Ok(())
+}
+
+/// values can be signed or unsigned, as long as they fit
+pub fn to_byte(value: i64) -> Result<u8> {
+    u8::try_from(value).or_else(|_| i8::try_from(value).map(|v| v as u8)).map_err(|_| AvcErr::ValueTooLarge(value))
+}
+pub fn to_short(value: i64) -> Result<u16> {
+    u16::try_from(value).or_else(|_| i16::try_from(value).map(|v| v as u16)).map_err(|_| AvcErr::ValueTooLarge(value))
+}
+
 pub type Result<T> = std::result::Result<T, AvcErr>;
 
 #[derive(PartialEq, Debug, Error)]
@@ -238,6 +445,12 @@ pub enum AvcErr {
     UnrecognisedDirective(String),
     #[error("malformed directive: {0}")]
     MalformedDirective(String),
+    #[error("duplicate label: {0}")]
+    DuplicateLabel(String),
+    #[error("duplicate constant: {0}")]
+    DuplicateConst(String),
+    #[error("sublabel &{0} used before any label")]
+    NoScope(String),
     #[error("undefined label: {0}")]
     UndefinedLabel(String),
     #[error("bad integer literal: {0}")]
@@ -246,8 +459,54 @@ pub enum AvcErr {
     OpNotInCodeSpace,
     #[error("relative jump too large")]
     RelJumpTooLarge,
+    #[error("value doesn't fit: {0}")]
+    ValueTooLarge(i64),
+    #[error("expected to be at {0:04x}, actually at {1:04x}")]
+    PcMismatch(u16, usize),
+    #[error("undefined struct: {0}")]
+    UndefinedStruct(String),
+    #[error("undefined region: {0}")]
+    UndefinedRegion(String),
+    #[error("no room in {0} for {1}")]
+    RegionFull(String, String),
     #[error("undefined macro: {0}")]
     UndefinedMacro(String),
+    #[error("wrong number of macro arguments: {0}")]
+    MacroArgCount(usize),
+    #[error("macros nested more than {0} deep")]
+    MacroDepth(usize),
+    #[error("in {0}, line {1}: {2}")]
+    InExpansion(String, i32, Box<AvcErr>),
+    #[error("line {0}: {1}")]
+    OnLine(i32, Box<AvcErr>),
+    #[error("undefined symbol: {0}")]
+    UndefinedSymbol(String),
+    #[error("bad expression: {0}")]
+    BadExpr(String),
+    #[error("divide by zero")]
+    DivideByZero,
+    #[error("{0} without .if")]
+    UnmatchedConditional(String),
+    #[error(".if without .endif")]
+    UnterminatedIf,
+    #[error("bad string: {0}")]
+    BadString(String),
+    #[error("bad escape sequence: {0}")]
+    BadEscape(String),
+    #[error("can't be relocated: {0}")]
+    NotRelocatable(String),
+    #[error("bad object file: {0}")]
+    BadObject(String),
+    #[error("bad linker script: {0}")]
+    BadScript(String),
+    #[error("linker script doesn't place section {0}")]
+    UnplacedSection(String),
+    #[error("section {0} doesn't fit in region {1}")]
+    RegionOverflow(String, String),
+    #[error("section {0} is {1:04x} bytes, more than the maximum {2:04x}")]
+    SectionTooBig(String, usize, usize),
+    #[error("section {0} overlaps another section")]
+    SectionOverlap(String),
     #[error("multibyte char: {0}")]
     MultibyteChar(char)
 }
@@ -343,11 +602,49 @@ next //end");
         assert_eq!(t.next(), None);
     }
 
+    #[test]
+    fn chars() {
+        let mut t = Tokeniser::new("LIT '{ JMP '} \"{abc %E('}) NOP");
+        let tokens: Vec<_> = t.by_ref().map(|t| t.unwrap().token).collect();
+        assert_eq!(tokens, vec!["LIT", "'{", "JMP", "'}", "\"{abc", "%E('})", "NOP"]);
+        assert_eq!(split_bracket_groups("'{, '(, ',", ','), vec!["'{", "'(", "',"]);
+        // only at the start of a word
+        let mut t = Tokeniser::new(".s(a') NOP");
+        assert_eq!(t.next(), Some(Ok(Token { token: ".s(a')", line: 0 })));
+        assert_eq!(t.next(), Some(Ok(Token { token: "NOP", line: 0 })));
+    }
+
     #[test]
     fn sbg() {
         assert_eq!(
             split_bracket_groups("abc, (123, 456), xyz", ','),
             vec!["abc", "(123, 456)", "xyz"]
+        );
+        assert_eq!(
+            split_bracket_groups("{a, (}, %M(b, c)", ','),
+            vec!["{a, (}", "%M(b, c)"]
         )
     }
+
+    #[test]
+    fn strings() {
+        let mut t = Tokeniser::new(r#".s("a ) \" b") next"#);
+        assert_eq!(t.next(), Some(Ok(Token { token: r#".s("a ) \" b")"#, line: 0 })));
+        assert_eq!(t.next(), Some(Ok(Token { token: "next", line: 0 })));
+        assert_eq!(parse_string(r#""a b\n\t\0\x41\\\"""#), Ok(b"a b\n\t\0A\\\"".to_vec()));
+        assert_eq!(parse_string(r#""\q""#), Err(AvcErr::BadEscape("\\q".into())));
+        assert_eq!(parse_string(r#""\xg1""#), Err(AvcErr::BadEscape("\\xg1".into())));
+        assert_eq!(split_bracket_groups(r#""a, b", c"#, ','), vec![r#""a, b""#, "c"]);
+        let mut t = Tokeniser::new(r#"%E('") NOP"#);
+        assert_eq!(t.next(), Some(Ok(Token { token: r#"%E('")"#, line: 0 })));
+        assert_eq!(t.next(), Some(Ok(Token { token: "NOP", line: 0 })));
+    }
+
+    #[test]
+    fn unwrap() {
+        assert_eq!(unwrap_group("(LIT #01, LIT #02)"), "LIT #01, LIT #02");
+        assert_eq!(unwrap_group("{ ) }"), ")");
+        assert_eq!(unwrap_group("(a) (b)"), "(a) (b)");
+        assert_eq!(unwrap_group("%M(a)"), "%M(a)");
+    }
 }